@@ -0,0 +1,286 @@
+//! Off-chain client for the bi-directional payment channel program.
+//!
+//! [`ChannelState`] mirrors the on-chain channel so that users can agree on balance updates off-chain. An update is
+//! only committed on-chain once both the users have signed their `challenge_exit` instruction for it, which
+//! [`ChannelState::update_transaction`] bundles into a single transaction for both of them to sign.
+
+use anchor_lang::{AnchorDeserialize, InstructionData, ToAccountMetas};
+use bi_directional_payment_channel::{accounts, instruction, Channel, CHANNEL_SEED, TREASURY_SEED};
+use solana_sdk::{instruction::Instruction, pubkey::Pubkey, system_program, transaction::Transaction};
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ClientError {
+    #[error("channel users must be different")]
+    DuplicateUsers,
+    #[error("not a user of the channel")]
+    NotUser,
+    #[error("balance overflow")]
+    BalanceOverflow,
+    #[error("insufficient balance")]
+    InsufficientBalance,
+    #[error("update does not preserve the total balance of the channel")]
+    TotalBalanceMismatch,
+    #[error("update nonce must be higher than the channel nonce")]
+    InvalidNonce,
+    #[error("transaction must contain exactly two challenge exit instructions")]
+    InvalidInstructions,
+    #[error("challenge exit instructions do not agree on channel, balances or nonce")]
+    MismatchedInstructions,
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+pub fn channel_address() -> Pubkey {
+    Pubkey::find_program_address(&[CHANNEL_SEED], &bi_directional_payment_channel::id()).0
+}
+
+pub fn treasury_address() -> Pubkey {
+    Pubkey::find_program_address(&[TREASURY_SEED], &bi_directional_payment_channel::id()).0
+}
+
+pub fn initialize(
+    authority: &Pubkey,
+    users: [Pubkey; 2],
+    balances: [u64; 2],
+    challenge_period: u64,
+    expires_at: u64,
+) -> Instruction {
+    Instruction {
+        program_id: bi_directional_payment_channel::id(),
+        accounts: accounts::Initialize {
+            channel: channel_address(),
+            treasury: treasury_address(),
+            authority: *authority,
+            system_program: system_program::id(),
+        }.to_account_metas(None),
+        data: instruction::Initialize { users, balances, challenge_period, expires_at }.data(),
+    }
+}
+
+pub fn challenge_exit(user: &Pubkey, balances: [u64; 2], nonce: u64) -> Instruction {
+    Instruction {
+        program_id: bi_directional_payment_channel::id(),
+        accounts: accounts::ChallengeExit {
+            channel: channel_address(),
+            treasury: treasury_address(),
+            user: *user,
+        }.to_account_metas(None),
+        data: instruction::ChallengeExit { balances, nonce }.data(),
+    }
+}
+
+pub fn withdraw(user: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: bi_directional_payment_channel::id(),
+        accounts: accounts::Withdraw {
+            channel: channel_address(),
+            treasury: treasury_address(),
+            user: *user,
+        }.to_account_metas(None),
+        data: instruction::Withdraw {}.data(),
+    }
+}
+
+pub fn deposit_treasury(payer: &Pubkey, lamports: u64) -> Instruction {
+    Instruction {
+        program_id: bi_directional_payment_channel::id(),
+        accounts: accounts::DepositTreasury {
+            channel: channel_address(),
+            treasury: treasury_address(),
+            payer: *payer,
+            system_program: system_program::id(),
+        }.to_account_metas(None),
+        data: instruction::DepositTreasury { lamports }.data(),
+    }
+}
+
+/// A balance update both the users need to agree on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Update {
+    pub balances: [u64; 2],
+    pub nonce: u64,
+}
+
+/// An update approved by some of the users.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingUpdate {
+    pub update: Update,
+    pub approved_by: [bool; 2],
+}
+
+/// Local model of the channel state machine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelState {
+    pub users: [Pubkey; 2],
+    pub balances: [u64; 2],
+    pub nonce: u64,
+    pub pending: Option<PendingUpdate>,
+}
+
+impl ChannelState {
+    pub fn new(users: [Pubkey; 2], balances: [u64; 2]) -> Result<Self> {
+        if users[0] == users[1] {
+            return Err(ClientError::DuplicateUsers);
+        }
+        total_balance(balances)?;
+
+        Ok(ChannelState {
+            users,
+            balances,
+            nonce: 0,
+            pending: None,
+        })
+    }
+
+    pub fn from_channel(channel: &Channel) -> Self {
+        let pending = channel.users
+            .iter()
+            .position(|u| channel.new_proposer != Pubkey::default() && u == &channel.new_proposer)
+            .map(|idx| {
+                let mut approved_by = [false, false];
+                approved_by[idx] = true;
+                PendingUpdate {
+                    update: Update { balances: channel.new_balances, nonce: channel.new_nonce },
+                    approved_by,
+                }
+            });
+
+        ChannelState {
+            users: channel.users,
+            balances: channel.balances,
+            nonce: channel.nonce,
+            pending,
+        }
+    }
+
+    pub fn total_balance(&self) -> Result<u64> {
+        total_balance(self.balances)
+    }
+
+    pub fn user_index(&self, user: &Pubkey) -> Result<usize> {
+        self.users.iter().position(|u| u == user).ok_or(ClientError::NotUser)
+    }
+
+    /// Builds the update in which `from` pays `amount` to the other user.
+    pub fn pay(&self, from: &Pubkey, amount: u64) -> Result<Update> {
+        let from_idx = self.user_index(from)?;
+        let mut balances = self.balances;
+        balances[from_idx] = balances[from_idx]
+            .checked_sub(amount)
+            .ok_or(ClientError::InsufficientBalance)?;
+        balances[1 - from_idx] = balances[1 - from_idx]
+            .checked_add(amount)
+            .ok_or(ClientError::BalanceOverflow)?;
+
+        let nonce = self.nonce.checked_add(1).ok_or(ClientError::InvalidNonce)?;
+        let update = Update { balances, nonce };
+        self.verify_update(&update)?;
+        Ok(update)
+    }
+
+    /// Verifies that the update moves the channel forward and preserves its total balance. Users should call this
+    /// before signing an update proposed by the other user.
+    pub fn verify_update(&self, update: &Update) -> Result<()> {
+        if update.nonce <= self.nonce {
+            return Err(ClientError::InvalidNonce);
+        }
+        if total_balance(update.balances)? != self.total_balance()? {
+            return Err(ClientError::TotalBalanceMismatch);
+        }
+        Ok(())
+    }
+
+    /// Records the approval of an update by a user, the same way `challenge_exit` does on-chain. Returns whether the
+    /// update was applied, which happens once both the users have approved it.
+    pub fn approve(&mut self, user: &Pubkey, update: Update) -> Result<bool> {
+        let idx = self.user_index(user)?;
+        self.verify_update(&update)?;
+
+        let mut pending = match self.pending {
+            Some(pending) if pending.update == update => pending,
+            _ => PendingUpdate { update, approved_by: [false, false] },
+        };
+        pending.approved_by[idx] = true;
+
+        if pending.approved_by == [true, true] {
+            self.balances = update.balances;
+            self.nonce = update.nonce;
+            self.pending = None;
+            Ok(true)
+        } else {
+            self.pending = Some(pending);
+            Ok(false)
+        }
+    }
+
+    pub fn challenge_exit_instructions(&self, update: &Update) -> Result<[Instruction; 2]> {
+        self.verify_update(update)?;
+        Ok([
+            challenge_exit(&self.users[0], update.balances, update.nonce),
+            challenge_exit(&self.users[1], update.balances, update.nonce),
+        ])
+    }
+
+    /// Builds the unsigned transaction committing the update. Both the users sign it with
+    /// [`Transaction::partial_sign`], using a durable transaction nonce if it is meant to be submitted later.
+    pub fn update_transaction(&self, update: &Update, fee_payer: &Pubkey) -> Result<Transaction> {
+        let instructions = self.challenge_exit_instructions(update)?;
+        Ok(Transaction::new_with_payer(&instructions, Some(fee_payer)))
+    }
+}
+
+/// Extracts the channel, users and update committed by a transaction built with
+/// [`ChannelState::update_transaction`]. Signatures are not verified.
+pub fn parse_update_transaction(transaction: &Transaction) -> Result<(Pubkey, [Pubkey; 2], Update)> {
+    let message = &transaction.message;
+    let mut exits = Vec::with_capacity(2);
+    for ix in &message.instructions {
+        if message.account_keys[ix.program_id_index as usize] != bi_directional_payment_channel::id() {
+            continue;
+        }
+
+        let args = decode_challenge_exit(&ix.data).ok_or(ClientError::InvalidInstructions)?;
+        if ix.accounts.len() != 3 {
+            return Err(ClientError::InvalidInstructions);
+        }
+
+        let user_idx = ix.accounts[2] as usize;
+        if !message.is_signer(user_idx) {
+            return Err(ClientError::InvalidInstructions);
+        }
+        exits.push((
+            message.account_keys[ix.accounts[0] as usize],
+            message.account_keys[user_idx],
+            Update { balances: args.balances, nonce: args.nonce },
+        ));
+    }
+
+    if exits.len() != 2 {
+        return Err(ClientError::InvalidInstructions);
+    }
+
+    let (channel, user0, update0) = exits[0];
+    let (other_channel, user1, update1) = exits[1];
+    if channel != other_channel || user0 == user1 || update0 != update1 {
+        return Err(ClientError::MismatchedInstructions);
+    }
+    Ok((channel, [user0, user1], update0))
+}
+
+fn decode_challenge_exit(data: &[u8]) -> Option<instruction::ChallengeExit> {
+    if data.len() < 8 {
+        return None;
+    }
+
+    let args = instruction::ChallengeExit::deserialize(&mut &data[8..]).ok()?;
+    if args.data() == data {
+        Some(args)
+    } else {
+        None
+    }
+}
+
+fn total_balance(balances: [u64; 2]) -> Result<u64> {
+    balances[0].checked_add(balances[1]).ok_or(ClientError::BalanceOverflow)
+}
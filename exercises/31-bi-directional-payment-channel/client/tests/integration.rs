@@ -0,0 +1,41 @@
+use {
+    channel_client::{channel_address, parse_update_transaction, ChannelState, ClientError, Update},
+    solana_sdk::pubkey::Pubkey,
+};
+
+#[test]
+fn test_channel_state() {
+    let users = [Pubkey::new_unique(), Pubkey::new_unique()];
+    let mut state = ChannelState::new(users, [100, 200]).unwrap();
+
+    let update = state.pay(&users[1], 50).unwrap();
+    assert_eq!(update, Update { balances: [150, 150], nonce: 1 });
+    assert_eq!(state.pay(&users[0], 101), Err(ClientError::InsufficientBalance));
+    assert_eq!(state.pay(&Pubkey::new_unique(), 1), Err(ClientError::NotUser));
+
+    assert_eq!(
+        state.verify_update(&Update { balances: [200, 200], nonce: 1 }),
+        Err(ClientError::TotalBalanceMismatch),
+    );
+    assert_eq!(
+        state.verify_update(&Update { balances: [300, 0], nonce: 0 }),
+        Err(ClientError::InvalidNonce),
+    );
+
+    assert_eq!(state.approve(&users[1], update), Ok(false));
+    assert_eq!(state.pending.unwrap().approved_by, [false, true]);
+    assert_eq!(state.approve(&users[0], update), Ok(true));
+    assert_eq!(state.balances, [150, 150]);
+    assert_eq!(state.nonce, 1);
+    assert_eq!(state.pending, None);
+
+    let transaction = state.update_transaction(&state.pay(&users[0], 150).unwrap(), &users[0]).unwrap();
+    let (channel, parsed_users, parsed_update) = parse_update_transaction(&transaction).unwrap();
+    assert_eq!(channel, channel_address());
+    assert_eq!(parsed_users, users);
+    assert_eq!(parsed_update, Update { balances: [0, 300], nonce: 2 });
+
+    // The nonce of a new update cannot wrap around to one lower than the channel nonce.
+    state.nonce = u64::MAX;
+    assert_eq!(state.pay(&users[0], 1), Err(ClientError::InvalidNonce));
+}
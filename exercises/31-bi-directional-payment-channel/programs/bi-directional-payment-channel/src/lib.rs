@@ -29,40 +29,12 @@ pub mod bi_directional_payment_channel {
         let now = Clock::get()?.unix_timestamp as u64;
         require!(expires_at > now, ChannelError::InvalidChallengePeriod);
 
-        let treasury = &mut ctx.accounts.treasury;
-        if treasury.owner != &crate::id() && treasury.owner != &System::id() {
-            return Err(anchor_lang::error::Error::from(ProgramError::IllegalOwner));
-        }
-        if treasury.lamports() > 0 || !treasury.data_is_empty() {
-            return Err(anchor_lang::error::Error::from(ProgramError::AccountAlreadyInitialized));
-        }
-
-        let balance_needed = calculate_total_balance_needed(balances)?;
-        let (treasury_key, treasury_bump_seed) = Pubkey::find_program_address(
-            &[TREASURY_SEED],
-            &crate::id(),
-        );
-        if &treasury_key != treasury.key {
-            msg!("Treasury account is invalid");
-            return Err(anchor_lang::error::Error::from(ProgramError::InvalidArgument));
-        }
-
         let authority = &ctx.accounts.authority;
-        let system_program = &ctx.accounts.system_program;
-        invoke_signed(
-            &system_instruction::create_account(
-                authority.key,
-                treasury.key,
-                balance_needed,
-                0,
-                &crate::id(),
-            ),
-            &[
-                authority.to_account_info(),
-                treasury.to_account_info(),
-                system_program.to_account_info(),
-            ],
-            &[&[TREASURY_SEED, &[treasury_bump_seed]]]
+        let treasury_bump_seed = create_treasury(
+            &ctx.accounts.treasury,
+            authority,
+            &ctx.accounts.system_program,
+            calculate_total_balance_needed(balances)?,
         )?;
 
         let channel = &mut ctx.accounts.channel;
@@ -77,6 +49,54 @@ pub mod bi_directional_payment_channel {
         channel.new_nonce = 0;
         channel.bump_seed = *ctx.bumps.get("channel").unwrap();
         channel.treasury_bump_seed = treasury_bump_seed;
+        channel.mode = ChannelMode::BiDirectional;
+        channel.stream = Stream::default();
+        Ok(())
+    }
+
+    pub fn initialize_stream(
+        ctx: Context<Initialize>,
+        payee: Pubkey,
+        amount: u64,
+        start_at: u64,
+        end_at: u64,
+    ) -> Result<()> {
+        let authority = &ctx.accounts.authority;
+        require!(payee != authority.key(), ChannelError::DuplicateUsers);
+        require!(amount > 0 && start_at < end_at, ChannelError::InvalidStream);
+
+        let now = Clock::get()?.unix_timestamp as u64;
+        require!(end_at > now, ChannelError::InvalidExpiresAt);
+
+        let balances = [amount, 0];
+        let treasury_bump_seed = create_treasury(
+            &ctx.accounts.treasury,
+            authority,
+            &ctx.accounts.system_program,
+            calculate_total_balance_needed(balances)?,
+        )?;
+
+        let channel = &mut ctx.accounts.channel;
+        channel.authority = authority.key();
+        channel.users = [authority.key(), payee];
+        channel.balances = balances;
+        channel.challenge_period = 0;
+        channel.expires_at = end_at;
+        channel.nonce = 0;
+        channel.new_proposer = Pubkey::default();
+        channel.new_balances = [0, 0];
+        channel.new_nonce = 0;
+        channel.bump_seed = *ctx.bumps.get("channel").unwrap();
+        channel.treasury_bump_seed = treasury_bump_seed;
+        channel.mode = ChannelMode::Streaming;
+        channel.stream = Stream {
+            amount,
+            start_at,
+            end_at,
+            streamed: 0,
+            paused_at: 0,
+            cancelled: false,
+        };
         Ok(())
     }
 
@@ -86,6 +106,8 @@ pub mod bi_directional_payment_channel {
         nonce: u64,
     ) -> Result<()> {
         let channel = &mut ctx.accounts.channel;
+        require!(channel.mode == ChannelMode::BiDirectional, ChannelError::InvalidMode);
+
         let now = Clock::get()?.unix_timestamp as u64;
         require!(channel.expires_at > now, ChannelError::Expired);
         require!(channel.nonce < nonce, ChannelError::InvalidNonce);
@@ -123,10 +145,16 @@ pub mod bi_directional_payment_channel {
     pub fn withdraw(ctx: Context<Withdraw>) -> Result<()> {
         let channel = &mut ctx.accounts.channel;
         let now = Clock::get()?.unix_timestamp as u64;
-        require!(channel.expires_at <= now, ChannelError::NotExpired);
-
         let user = &ctx.accounts.user;
         let idx = find_user_index(channel.users, user.key)?;
+        if channel.mode == ChannelMode::Streaming {
+            sync_stream(channel, now)?;
+        }
+
+        // In streaming mode, the payee can withdraw the streamed funds anytime.
+        let is_payee = channel.mode == ChannelMode::Streaming && idx == 1;
+        require!(is_payee || channel.expires_at <= now, ChannelError::NotExpired);
+
         let balance = channel.balances[idx];
 
         channel.balances[idx] = 0;
@@ -160,6 +188,85 @@ pub mod bi_directional_payment_channel {
 
         Ok(())
     }
+
+    pub fn close_channel(ctx: Context<CloseChannel>) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+        let now = Clock::get()?.unix_timestamp as u64;
+        if channel.mode == ChannelMode::Streaming {
+            sync_stream(channel, now)?;
+        }
+        require!(channel.expires_at <= now, ChannelError::NotExpired);
+        require!(channel.balances == [0, 0], ChannelError::NotSettled);
+
+        // Drain the rent exempt minimum as well so that the treasury is garbage collected. The channel is closed by
+        // anchor, which also overwrites its discriminator so that it cannot be used again in the same transaction. Once
+        // both are garbage collected, `initialize` sets up a new channel from scratch at the same addresses.
+        let treasury = &mut ctx.accounts.treasury;
+        let lamports = treasury.lamports();
+        if lamports > 0 {
+            let authority = &ctx.accounts.authority;
+            transfer_from_treasury(treasury, authority, lamports)?;
+        }
+        Ok(())
+    }
+
+    pub fn pause_stream(ctx: Context<UpdateStream>) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+        require!(channel.mode == ChannelMode::Streaming, ChannelError::InvalidMode);
+
+        let now = Clock::get()?.unix_timestamp as u64;
+        require!(channel.expires_at > now, ChannelError::Expired);
+        require!(channel.stream.paused_at == 0, ChannelError::Paused);
+
+        sync_stream(channel, now)?;
+        channel.stream.paused_at = now;
+        // The stream cannot end while paused.
+        channel.expires_at = u64::MAX;
+        Ok(())
+    }
+
+    pub fn resume_stream(ctx: Context<UpdateStream>) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+        require!(channel.mode == ChannelMode::Streaming, ChannelError::InvalidMode);
+        require!(!channel.stream.cancelled, ChannelError::Expired);
+        require!(channel.stream.paused_at > 0, ChannelError::NotPaused);
+
+        // Shift the stream by the time it was paused after starting, so that the remaining amount streams at the same
+        // rate as before.
+        let now = Clock::get()?.unix_timestamp as u64;
+        let stream = &mut channel.stream;
+        let paused_for = now.max(stream.start_at) - stream.paused_at.max(stream.start_at);
+        stream.start_at = stream.start_at
+            .checked_add(paused_for)
+            .ok_or(error!(ChannelError::ExpiresAtOverflow))?;
+        stream.end_at = stream.end_at
+            .checked_add(paused_for)
+            .ok_or(error!(ChannelError::ExpiresAtOverflow))?;
+        stream.paused_at = 0;
+
+        channel.expires_at = channel.stream.end_at;
+        Ok(())
+    }
+
+    pub fn cancel_stream(ctx: Context<CancelStream>) -> Result<()> {
+        let channel = &mut ctx.accounts.channel;
+        require!(channel.mode == ChannelMode::Streaming, ChannelError::InvalidMode);
+
+        let user = &ctx.accounts.user;
+        verify_user(channel.users, user.key)?;
+
+        let now = Clock::get()?.unix_timestamp as u64;
+        require!(channel.expires_at > now, ChannelError::Expired);
+
+        // Freeze the stream at the current split, after which both the users can withdraw their shares.
+        sync_stream(channel, now)?;
+        if channel.stream.paused_at == 0 {
+            channel.stream.paused_at = now;
+        }
+        channel.stream.cancelled = true;
+        channel.expires_at = now;
+        Ok(())
+    }
 }
 
 #[derive(Accounts)]
@@ -219,6 +326,37 @@ pub struct WithdrawExcessTreasuryAuthority<'info> {
     pub authority: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct CloseChannel<'info> {
+    #[account(
+        mut,
+        has_one = authority,
+        close = authority,
+        seeds = [CHANNEL_SEED],
+        bump = channel.bump_seed,
+    )]
+    pub channel: Account<'info, Channel>,
+    /// CHECK: Treasury PDA without any associated data.
+    #[account(mut, seeds = [TREASURY_SEED], bump = channel.treasury_bump_seed)]
+    pub treasury: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateStream<'info> {
+    #[account(mut, has_one = authority, seeds = [CHANNEL_SEED], bump = channel.bump_seed)]
+    pub channel: Account<'info, Channel>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelStream<'info> {
+    #[account(mut, seeds = [CHANNEL_SEED], bump = channel.bump_seed)]
+    pub channel: Account<'info, Channel>,
+    pub user: Signer<'info>,
+}
+
 #[account]
 #[derive(Default)]
 pub struct Channel {
@@ -238,12 +376,54 @@ pub struct Channel {
 
     pub bump_seed: u8,          // 1
     pub treasury_bump_seed: u8, // 1
+
+    pub mode: ChannelMode,      // 1
+
+    // Streaming mode. Users are the payer and the payee, in that order.
+    pub stream: Stream,         // 41
 }
 
 impl Channel {
     pub const LEN: usize = std::mem::size_of::<Channel>();
 }
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelMode {
+    BiDirectional,
+    Streaming,
+}
+
+impl Default for ChannelMode {
+    fn default() -> Self {
+        ChannelMode::BiDirectional
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stream {
+    pub amount: u64,            // 8
+    pub start_at: u64,          // 8
+    pub end_at: u64,            // 8
+    pub streamed: u64,          // 8
+    pub paused_at: u64,         // 8
+    pub cancelled: bool,        // 1
+}
+
+impl Stream {
+    pub fn streamed_at(&self, now: u64) -> u64 {
+        let now = if self.paused_at > 0 { self.paused_at } else { now };
+        if now <= self.start_at {
+            0
+        } else if now >= self.end_at {
+            self.amount
+        } else {
+            let elapsed = (now - self.start_at) as u128;
+            let duration = (self.end_at - self.start_at) as u128;
+            (self.amount as u128 * elapsed / duration) as u64
+        }
+    }
+}
+
 #[error_code]
 pub enum ChannelError {
     InsufficientBalance,
@@ -257,6 +437,51 @@ pub enum ChannelError {
     Expired,
     NotExpired,
     ExpiresAtOverflow,
+    InvalidMode,
+    InvalidStream,
+    Paused,
+    NotPaused,
+    NotSettled,
+}
+
+fn create_treasury<'info>(
+    treasury: &UncheckedAccount<'info>,
+    authority: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    lamports: u64,
+) -> Result<u8> {
+    if treasury.owner != &crate::id() && treasury.owner != &System::id() {
+        return Err(anchor_lang::error::Error::from(ProgramError::IllegalOwner));
+    }
+    if treasury.lamports() > 0 || !treasury.data_is_empty() {
+        return Err(anchor_lang::error::Error::from(ProgramError::AccountAlreadyInitialized));
+    }
+
+    let (treasury_key, treasury_bump_seed) = Pubkey::find_program_address(
+        &[TREASURY_SEED],
+        &crate::id(),
+    );
+    if &treasury_key != treasury.key {
+        msg!("Treasury account is invalid");
+        return Err(anchor_lang::error::Error::from(ProgramError::InvalidArgument));
+    }
+
+    invoke_signed(
+        &system_instruction::create_account(
+            authority.key,
+            treasury.key,
+            lamports,
+            0,
+            &crate::id(),
+        ),
+        &[
+            authority.to_account_info(),
+            treasury.to_account_info(),
+            system_program.to_account_info(),
+        ],
+        &[&[TREASURY_SEED, &[treasury_bump_seed]]]
+    )?;
+    Ok(treasury_bump_seed)
 }
 
 fn transfer_to_treasury<'info>(
@@ -290,6 +515,21 @@ fn transfer_from_treasury<'info>(
     Ok(())
 }
 
+fn sync_stream(channel: &mut Channel, now: u64) -> Result<()> {
+    let streamed = channel.stream.streamed_at(now);
+    let delta = streamed
+        .checked_sub(channel.stream.streamed)
+        .ok_or(error!(ChannelError::InvalidStream))?;
+    let payer_balance = subtract_u64_to_i64(channel.balances[0], delta)?;
+    require!(payer_balance >= 0, ChannelError::InsufficientBalance);
+    channel.balances[0] = payer_balance as u64;
+    channel.balances[1] = channel.balances[1]
+        .checked_add(delta)
+        .ok_or(error!(ChannelError::BalanceOverflow))?;
+    channel.stream.streamed = streamed;
+    Ok(())
+}
+
 fn find_user_index(
     users: [Pubkey; 2],
     user: &Pubkey,
@@ -0,0 +1,472 @@
+use {
+    anchor_lang::{error::ERROR_CODE_OFFSET, AccountDeserialize, InstructionData, ToAccountMetas},
+    assert_matches::assert_matches,
+    bi_directional_payment_channel::{
+        self, accounts, instruction, Channel, ChannelError, CHANNEL_SEED, TREASURY_SEED,
+    },
+    solana_program_test::{processor, tokio, ProgramTest, ProgramTestContext},
+    solana_sdk::{
+        clock::Clock,
+        instruction::{Instruction, InstructionError},
+        native_token::LAMPORTS_PER_SOL,
+        pubkey::Pubkey,
+        rent::Rent,
+        signature::{Keypair, Signer},
+        system_program,
+        transaction::{Transaction, TransactionError},
+        transport::TransportError,
+    },
+};
+
+const SOL: u64 = LAMPORTS_PER_SOL;
+
+#[tokio::test]
+async fn test_challenge_period_and_expiry() {
+    let mut tc = TestingContext::new().await;
+    assert_matches!(tc.initialize([SOL, 2 * SOL], 100, 1000).await, Ok(()));
+
+    // Nobody can withdraw before the channel expires.
+    assert_channel_error(tc.withdraw(0).await, ChannelError::NotExpired);
+
+    // A committed update restarts the expiry with the challenge period.
+    let now = tc.now().await;
+    tc.co_sign([2 * SOL, SOL], 1).await;
+    let channel = tc.channel().await;
+    assert_eq!(channel.balances, [2 * SOL, SOL]);
+    assert_eq!(channel.nonce, 1);
+    assert_eq!(channel.expires_at, now + 100);
+
+    tc.warp(50).await;
+    assert_channel_error(tc.withdraw(0).await, ChannelError::NotExpired);
+
+    // The other user can still challenge within the challenge period.
+    tc.co_sign([SOL + SOL / 2, SOL + SOL / 2], 2).await;
+    let now = tc.now().await;
+    assert_eq!(tc.channel().await.expires_at, now + 100);
+
+    tc.warp(100).await;
+    assert_channel_error(tc.challenge_exit(0, [SOL, 2 * SOL], 3).await, ChannelError::Expired);
+
+    assert_matches!(tc.withdraw(0).await, Ok(()));
+    assert_matches!(tc.withdraw(1).await, Ok(()));
+    assert_eq!(tc.lamports(tc.users[0].pubkey()).await, SOL + SOL / 2);
+    assert_eq!(tc.lamports(tc.users[1].pubkey()).await, SOL + SOL / 2);
+    assert_eq!(tc.channel().await.balances, [0, 0]);
+}
+
+#[tokio::test]
+async fn test_initial_expiry() {
+    let mut tc = TestingContext::new().await;
+    assert_matches!(tc.initialize([SOL, 2 * SOL], 100, 1000).await, Ok(()));
+
+    // Without any update, the channel settles with the initial balances at the initial expiry.
+    tc.warp(999).await;
+    assert_channel_error(tc.withdraw(1).await, ChannelError::NotExpired);
+
+    tc.warp(1).await;
+    assert_channel_error(tc.challenge_exit(0, [2 * SOL, SOL], 1).await, ChannelError::Expired);
+    assert_matches!(tc.withdraw(1).await, Ok(()));
+    assert_eq!(tc.lamports(tc.users[1].pubkey()).await, 2 * SOL);
+}
+
+#[tokio::test]
+async fn test_expires_at_overflow() {
+    let mut tc = TestingContext::new().await;
+    assert_matches!(tc.initialize([SOL, 2 * SOL], u64::MAX, 1000).await, Ok(()));
+
+    assert_matches!(tc.challenge_exit(0, [2 * SOL, SOL], 1).await, Ok(()));
+    assert_channel_error(tc.challenge_exit(1, [2 * SOL, SOL], 1).await, ChannelError::ExpiresAtOverflow);
+}
+
+#[tokio::test]
+async fn test_nonce_replay() {
+    let mut tc = TestingContext::new().await;
+    assert_matches!(tc.initialize([SOL, 2 * SOL], 100, 1000).await, Ok(()));
+
+    tc.co_sign([2 * SOL, SOL], 2).await;
+    assert_channel_error(tc.challenge_exit(0, [2 * SOL, SOL], 2).await, ChannelError::InvalidNonce);
+    assert_channel_error(tc.challenge_exit(1, [SOL, 2 * SOL], 1).await, ChannelError::InvalidNonce);
+
+    // A proposal is only committed when both users agree on the same balances and nonce.
+    assert_matches!(tc.challenge_exit(0, [3 * SOL, 0], 3).await, Ok(()));
+    assert_matches!(tc.challenge_exit(1, [0, 3 * SOL], 3).await, Ok(()));
+    let channel = tc.channel().await;
+    assert_eq!(channel.nonce, 2);
+    assert_eq!(channel.new_proposer, tc.users[1].pubkey());
+    assert_eq!(channel.new_balances, [0, 3 * SOL]);
+
+    assert_channel_error(tc.challenge_exit_with(&Keypair::new(), [SOL, 2 * SOL], 4).await, ChannelError::NotUser);
+}
+
+#[tokio::test]
+async fn test_insufficient_treasury_balance() {
+    let mut tc = TestingContext::new().await;
+    assert_matches!(tc.initialize([SOL, 2 * SOL], 100, 1000).await, Ok(()));
+
+    assert_channel_error(tc.challenge_exit(0, [2 * SOL, 2 * SOL], 1).await, ChannelError::InsufficientBalance);
+
+    assert_matches!(tc.deposit_treasury(SOL).await, Ok(()));
+    tc.co_sign([2 * SOL, 2 * SOL], 1).await;
+    assert_eq!(tc.channel().await.balances, [2 * SOL, 2 * SOL]);
+}
+
+#[tokio::test]
+async fn test_withdraw_excess_treasury_authority() {
+    let mut tc = TestingContext::new().await;
+    assert_matches!(tc.initialize([SOL, 2 * SOL], 100, 1000).await, Ok(()));
+    assert_matches!(tc.deposit_treasury(2 * SOL).await, Ok(()));
+    tc.co_sign([SOL, SOL], 1).await;
+
+    let not_authority = Keypair::new();
+    assert_matches!(tc.withdraw_excess_treasury_authority(Some(&not_authority)).await, Err(_));
+
+    let treasury = tc.treasury;
+    let rent = tc.rent().await;
+    assert_eq!(tc.lamports(treasury).await, 5 * SOL + rent);
+    assert_matches!(tc.withdraw_excess_treasury_authority(None).await, Ok(()));
+    assert_eq!(tc.lamports(treasury).await, 2 * SOL + rent);
+
+    // Nothing more to withdraw, and the users still get their shares.
+    tc.warp(100).await;
+    assert_matches!(tc.withdraw_excess_treasury_authority(None).await, Ok(()));
+    assert_eq!(tc.lamports(treasury).await, 2 * SOL + rent);
+    assert_matches!(tc.withdraw(0).await, Ok(()));
+    assert_matches!(tc.withdraw(1).await, Ok(()));
+    assert_eq!(tc.lamports(treasury).await, rent);
+}
+
+#[tokio::test]
+async fn test_streaming() {
+    let mut tc = TestingContext::new().await;
+    let payer = Keypair::from_bytes(&tc.ctx.payer.to_bytes()).unwrap();
+    let payee = tc.users[1].pubkey();
+
+    // The authority streams 10 SOL to the payee over 100 seconds.
+    assert_matches!(tc.initialize_stream(payee, 10 * SOL, 0, 100).await, Ok(()));
+    assert_channel_error(tc.challenge_exit(1, [0, 10 * SOL], 1).await, ChannelError::InvalidMode);
+
+    tc.warp(25).await;
+    assert_matches!(tc.withdraw(1).await, Ok(()));
+    assert_eq!(tc.lamports(payee).await, 10 * SOL / 4);
+    assert_channel_error(tc.withdraw_with(&payer).await, ChannelError::NotExpired);
+
+    // Nothing streams while paused, and the end of the stream moves by the paused duration.
+    assert_matches!(tc.pause_stream().await, Ok(()));
+    assert_channel_error(tc.pause_stream().await, ChannelError::Paused);
+    tc.warp(50).await;
+    assert_matches!(tc.withdraw(1).await, Ok(()));
+    assert_eq!(tc.lamports(payee).await, 10 * SOL / 4);
+
+    let now = tc.now().await;
+    assert_matches!(tc.resume_stream().await, Ok(()));
+    assert_channel_error(tc.resume_stream().await, ChannelError::NotPaused);
+    assert_eq!(tc.channel().await.expires_at, now + 75);
+
+    // Cancelling splits the treasury pro-rata at that point.
+    tc.warp(25).await;
+    assert_matches!(tc.cancel_stream(1).await, Ok(()));
+    assert_eq!(tc.channel().await.balances, [10 * SOL / 2, 10 * SOL / 4]);
+    assert_channel_error(tc.resume_stream().await, ChannelError::Expired);
+
+    tc.warp(10).await;
+    assert_matches!(tc.withdraw(1).await, Ok(()));
+    assert_matches!(tc.withdraw_with(&payer).await, Ok(()));
+    assert_eq!(tc.lamports(payee).await, 10 * SOL / 2);
+    assert_eq!(tc.channel().await.balances, [0, 0]);
+
+    let treasury = tc.treasury;
+    let rent = tc.rent().await;
+    assert_eq!(tc.lamports(treasury).await, rent);
+}
+
+#[tokio::test]
+async fn test_close_channel() {
+    let mut tc = TestingContext::new().await;
+    assert_matches!(tc.initialize([SOL, 2 * SOL], 100, 1000).await, Ok(()));
+    assert_channel_error(tc.close_channel(None).await, ChannelError::NotExpired);
+
+    tc.warp(1000).await;
+    assert_channel_error(tc.close_channel(None).await, ChannelError::NotSettled);
+    assert_matches!(tc.withdraw(0).await, Ok(()));
+    assert_matches!(tc.withdraw(1).await, Ok(()));
+
+    let not_authority = Keypair::new();
+    assert_matches!(tc.close_channel(Some(&not_authority)).await, Err(_));
+    assert_matches!(tc.close_channel(None).await, Ok(()));
+
+    let (channel, treasury) = (tc.channel, tc.treasury);
+    assert_matches!(tc.ctx.banks_client.get_account(channel).await, Ok(None));
+    assert_eq!(tc.lamports(treasury).await, 0);
+
+    // The closed channel cannot be used anymore.
+    tc.warp(1).await;
+    assert_matches!(tc.withdraw(0).await, Err(_));
+
+    // A new channel starts from scratch at the same addresses.
+    assert_matches!(tc.initialize([SOL, SOL], 100, 1000).await, Ok(()));
+    let channel = tc.channel().await;
+    assert_eq!(channel.balances, [SOL, SOL]);
+    assert_eq!(channel.nonce, 0);
+    assert_eq!(channel.new_proposer, Pubkey::default());
+    assert_eq!(channel.new_nonce, 0);
+    let rent = tc.rent().await;
+    assert_eq!(tc.lamports(treasury).await, 2 * SOL + rent);
+}
+
+struct TestingContext {
+    ctx: ProgramTestContext,
+    users: [Keypair; 2],
+    channel: Pubkey,
+    treasury: Pubkey,
+}
+
+impl TestingContext {
+    async fn new() -> Self {
+        let pt = ProgramTest::new(
+            "bi_directional_payment_channel",
+            bi_directional_payment_channel::id(),
+            processor!(bi_directional_payment_channel::entry),
+        );
+        let ctx = pt.start_with_context().await;
+        let (channel, _) = Pubkey::find_program_address(&[CHANNEL_SEED], &bi_directional_payment_channel::id());
+        let (treasury, _) = Pubkey::find_program_address(&[TREASURY_SEED], &bi_directional_payment_channel::id());
+
+        TestingContext {
+            ctx,
+            users: [Keypair::new(), Keypair::new()],
+            channel,
+            treasury,
+        }
+    }
+
+    async fn now(&mut self) -> u64 {
+        let clock: Clock = self.ctx.banks_client.get_sysvar().await.unwrap();
+        clock.unix_timestamp as u64
+    }
+
+    /// Moves to the next slot with the clock moved forward by the given number of seconds.
+    async fn warp(&mut self, seconds: u64) {
+        let mut clock: Clock = self.ctx.banks_client.get_sysvar().await.unwrap();
+        clock.slot += 1;
+        clock.unix_timestamp += seconds as i64;
+        self.ctx.warp_to_slot(clock.slot).unwrap();
+        self.ctx.set_sysvar(&clock);
+        self.ctx.last_blockhash = self.ctx.banks_client.get_latest_blockhash().await.unwrap();
+    }
+
+    async fn rent(&mut self) -> u64 {
+        let rent: Rent = self.ctx.banks_client.get_sysvar().await.unwrap();
+        rent.minimum_balance(0)
+    }
+
+    async fn process(&mut self, instruction: Instruction, signer: Option<&Keypair>) -> Result<(), TransportError> {
+        let payer = &self.ctx.payer;
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&payer.pubkey()));
+        match signer {
+            Some(signer) if signer.pubkey() != payer.pubkey() => {
+                transaction.sign(&[payer, signer], self.ctx.last_blockhash)
+            }
+            _ => transaction.sign(&[payer], self.ctx.last_blockhash),
+        }
+        self.ctx.banks_client.process_transaction(transaction).await
+    }
+
+    async fn initialize(
+        &mut self,
+        balances: [u64; 2],
+        challenge_period: u64,
+        expires_in: u64,
+    ) -> Result<(), TransportError> {
+        let expires_at = self.now().await + expires_in;
+        let instruction = Instruction {
+            program_id: bi_directional_payment_channel::id(),
+            accounts: accounts::Initialize {
+                channel: self.channel,
+                treasury: self.treasury,
+                authority: self.ctx.payer.pubkey(),
+                system_program: system_program::id(),
+            }.to_account_metas(None),
+            data: instruction::Initialize {
+                users: [self.users[0].pubkey(), self.users[1].pubkey()],
+                balances,
+                challenge_period,
+                expires_at,
+            }.data(),
+        };
+        self.process(instruction, None).await
+    }
+
+    async fn initialize_stream(
+        &mut self,
+        payee: Pubkey,
+        amount: u64,
+        starts_in: u64,
+        ends_in: u64,
+    ) -> Result<(), TransportError> {
+        let now = self.now().await;
+        let instruction = Instruction {
+            program_id: bi_directional_payment_channel::id(),
+            accounts: accounts::Initialize {
+                channel: self.channel,
+                treasury: self.treasury,
+                authority: self.ctx.payer.pubkey(),
+                system_program: system_program::id(),
+            }.to_account_metas(None),
+            data: instruction::InitializeStream {
+                payee,
+                amount,
+                start_at: now + starts_in,
+                end_at: now + ends_in,
+            }.data(),
+        };
+        self.process(instruction, None).await
+    }
+
+    async fn challenge_exit(&mut self, user_idx: usize, balances: [u64; 2], nonce: u64) -> Result<(), TransportError> {
+        let user = Keypair::from_bytes(&self.users[user_idx].to_bytes()).unwrap();
+        self.challenge_exit_with(&user, balances, nonce).await
+    }
+
+    async fn challenge_exit_with(
+        &mut self,
+        user: &Keypair,
+        balances: [u64; 2],
+        nonce: u64,
+    ) -> Result<(), TransportError> {
+        let instruction = Instruction {
+            program_id: bi_directional_payment_channel::id(),
+            accounts: accounts::ChallengeExit {
+                channel: self.channel,
+                treasury: self.treasury,
+                user: user.pubkey(),
+            }.to_account_metas(None),
+            data: instruction::ChallengeExit { balances, nonce }.data(),
+        };
+        self.process(instruction, Some(user)).await
+    }
+
+    /// Commits an update signed by both the users.
+    async fn co_sign(&mut self, balances: [u64; 2], nonce: u64) {
+        assert_matches!(self.challenge_exit(0, balances, nonce).await, Ok(()));
+        assert_matches!(self.challenge_exit(1, balances, nonce).await, Ok(()));
+    }
+
+    async fn withdraw(&mut self, user_idx: usize) -> Result<(), TransportError> {
+        let user = Keypair::from_bytes(&self.users[user_idx].to_bytes()).unwrap();
+        self.withdraw_with(&user).await
+    }
+
+    async fn withdraw_with(&mut self, user: &Keypair) -> Result<(), TransportError> {
+        let instruction = Instruction {
+            program_id: bi_directional_payment_channel::id(),
+            accounts: accounts::Withdraw {
+                channel: self.channel,
+                treasury: self.treasury,
+                user: user.pubkey(),
+            }.to_account_metas(None),
+            data: instruction::Withdraw {}.data(),
+        };
+        self.process(instruction, Some(user)).await
+    }
+
+    async fn deposit_treasury(&mut self, lamports: u64) -> Result<(), TransportError> {
+        let instruction = Instruction {
+            program_id: bi_directional_payment_channel::id(),
+            accounts: accounts::DepositTreasury {
+                channel: self.channel,
+                treasury: self.treasury,
+                payer: self.ctx.payer.pubkey(),
+                system_program: system_program::id(),
+            }.to_account_metas(None),
+            data: instruction::DepositTreasury { lamports }.data(),
+        };
+        self.process(instruction, None).await
+    }
+
+    async fn withdraw_excess_treasury_authority(
+        &mut self,
+        authority: Option<&Keypair>,
+    ) -> Result<(), TransportError> {
+        let authority_key = authority.map_or(self.ctx.payer.pubkey(), |a| a.pubkey());
+        let instruction = Instruction {
+            program_id: bi_directional_payment_channel::id(),
+            accounts: accounts::WithdrawExcessTreasuryAuthority {
+                channel: self.channel,
+                treasury: self.treasury,
+                authority: authority_key,
+            }.to_account_metas(None),
+            data: instruction::WithdrawExcessTreasuryAuthority {}.data(),
+        };
+        self.process(instruction, authority).await
+    }
+
+    async fn close_channel(&mut self, authority: Option<&Keypair>) -> Result<(), TransportError> {
+        let authority_key = authority.map_or(self.ctx.payer.pubkey(), |a| a.pubkey());
+        let instruction = Instruction {
+            program_id: bi_directional_payment_channel::id(),
+            accounts: accounts::CloseChannel {
+                channel: self.channel,
+                treasury: self.treasury,
+                authority: authority_key,
+            }.to_account_metas(None),
+            data: instruction::CloseChannel {}.data(),
+        };
+        self.process(instruction, authority).await
+    }
+
+    async fn pause_stream(&mut self) -> Result<(), TransportError> {
+        let instruction = Instruction {
+            program_id: bi_directional_payment_channel::id(),
+            accounts: accounts::UpdateStream {
+                channel: self.channel,
+                authority: self.ctx.payer.pubkey(),
+            }.to_account_metas(None),
+            data: instruction::PauseStream {}.data(),
+        };
+        self.process(instruction, None).await
+    }
+
+    async fn resume_stream(&mut self) -> Result<(), TransportError> {
+        let instruction = Instruction {
+            program_id: bi_directional_payment_channel::id(),
+            accounts: accounts::UpdateStream {
+                channel: self.channel,
+                authority: self.ctx.payer.pubkey(),
+            }.to_account_metas(None),
+            data: instruction::ResumeStream {}.data(),
+        };
+        self.process(instruction, None).await
+    }
+
+    async fn cancel_stream(&mut self, user_idx: usize) -> Result<(), TransportError> {
+        let user = Keypair::from_bytes(&self.users[user_idx].to_bytes()).unwrap();
+        let instruction = Instruction {
+            program_id: bi_directional_payment_channel::id(),
+            accounts: accounts::CancelStream {
+                channel: self.channel,
+                user: user.pubkey(),
+            }.to_account_metas(None),
+            data: instruction::CancelStream {}.data(),
+        };
+        self.process(instruction, Some(&user)).await
+    }
+
+    async fn channel(&mut self) -> Channel {
+        let account = self.ctx.banks_client.get_account(self.channel).await.unwrap().unwrap();
+        Channel::try_deserialize(&mut account.data.as_slice()).unwrap()
+    }
+
+    async fn lamports(&mut self, key: Pubkey) -> u64 {
+        self.ctx.banks_client.get_balance(key).await.unwrap()
+    }
+}
+
+fn assert_channel_error(result: Result<(), TransportError>, error: ChannelError) {
+    assert_matches!(
+        result,
+        Err(TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(code),
+        ))) if code == ERROR_CODE_OFFSET + error as u32
+    );
+}
@@ -218,6 +218,213 @@ describe("bi-directional-payment-channel", () => {
     console.log(`Final balance: ${
       (await provider.connection.getBalance(treasury)) / web3.LAMPORTS_PER_SOL
     } SOL`);
+
+    await program.rpc.closeChannel(
+      {
+        accounts: {
+          channel,
+          treasury,
+          authority: wallet.publicKey,
+        },
+        signers: [],
+      },
+    );
+
+    assert.isNull(await program.account.channel.fetchNullable(channel));
+    assert.equal(await provider.connection.getBalance(treasury), 0);
+  });
+
+  it("Supports pausing, resuming and cancelling a stream", async () => {
+    const [channel] = await web3.PublicKey.findProgramAddress(
+      [Buffer.from("channel")],
+      program.programId,
+    );
+
+    const [treasury] = await web3.PublicKey.findProgramAddress(
+      [Buffer.from("treasury")],
+      program.programId,
+    );
+
+    // Start the stream in the past so that some of it has already streamed when it gets paused.
+    const now = Math.floor(Date.now() / 1000);
+    await program.rpc.initializeStream(
+      user2.publicKey,
+      new BN(SOL),
+      new BN(now - 10),
+      new BN(now + 90),
+      {
+        accounts: {
+          channel,
+          treasury,
+          authority: wallet.publicKey,
+          systemProgram: web3.SystemProgram.programId,
+        },
+      },
+    );
+
+    let channelAccount = await program.account.channel.fetch(channel);
+    assertDeepEq(channelAccount.users, [wallet.publicKey, user2.publicKey]);
+    assert(channelAccount.balances[0].eq(new BN(SOL)));
+    assert(channelAccount.balances[1].eq(new BN(0)));
+    assert(channelAccount.expiresAt.eq(new BN(now + 90)));
+    assert(channelAccount.stream.pausedAt.eq(new BN(0)));
+    assert.isFalse(channelAccount.stream.cancelled);
+
+    let rejected = false;
+    try {
+      await program.rpc.resumeStream(
+        {
+          accounts: {
+            channel,
+            authority: wallet.publicKey,
+          },
+        },
+      );
+    } catch (e: any) {
+      rejected = true;
+      expect(e?.code).to.eq(6014);
+    }
+    assert.isTrue(rejected);
+
+    await program.rpc.pauseStream(
+      {
+        accounts: {
+          channel,
+          authority: wallet.publicKey,
+        },
+      },
+    );
+
+    channelAccount = await program.account.channel.fetch(channel);
+    const streamed = channelAccount.stream.streamed;
+    assert(streamed.gt(new BN(0)));
+    assert(channelAccount.balances[0].eq(new BN(SOL).sub(streamed)));
+    assert(channelAccount.balances[1].eq(streamed));
+    assert(channelAccount.stream.pausedAt.gt(new BN(0)));
+    assert(channelAccount.expiresAt.eq(new BN("18446744073709551615")));
+
+    rejected = false;
+    try {
+      await program.rpc.pauseStream(
+        {
+          accounts: {
+            channel,
+            authority: wallet.publicKey,
+          },
+        },
+      );
+    } catch (e: any) {
+      rejected = true;
+      expect(e?.code).to.eq(6013);
+    }
+    assert.isTrue(rejected);
+
+    await program.rpc.resumeStream(
+      {
+        accounts: {
+          channel,
+          authority: wallet.publicKey,
+        },
+      },
+    );
+
+    channelAccount = await program.account.channel.fetch(channel);
+    assert(channelAccount.stream.pausedAt.eq(new BN(0)));
+    assert(channelAccount.expiresAt.eq(channelAccount.stream.endAt));
+    assert(channelAccount.stream.endAt.gte(new BN(now + 90)));
+
+    // Only the users of the channel can cancel the stream.
+    rejected = false;
+    try {
+      await program.rpc.cancelStream(
+        {
+          accounts: {
+            channel,
+            user: user1.publicKey,
+          },
+          signers: [user1],
+        },
+      );
+    } catch (e: any) {
+      rejected = true;
+      expect(e?.code).to.eq(6007);
+    }
+    assert.isTrue(rejected);
+
+    await program.rpc.cancelStream(
+      {
+        accounts: {
+          channel,
+          user: user2.publicKey,
+        },
+        signers: [user2],
+      },
+    );
+
+    channelAccount = await program.account.channel.fetch(channel);
+    assert.isTrue(channelAccount.stream.cancelled);
+    assert(channelAccount.stream.pausedAt.gt(new BN(0)));
+    assert(channelAccount.stream.streamed.gte(streamed));
+    assert(channelAccount.balances[0].add(channelAccount.balances[1]).eq(new BN(SOL)));
+
+    rejected = false;
+    try {
+      await program.rpc.resumeStream(
+        {
+          accounts: {
+            channel,
+            authority: wallet.publicKey,
+          },
+        },
+      );
+    } catch (e: any) {
+      rejected = true;
+      expect(e?.code).to.eq(6008);
+    }
+    assert.isTrue(rejected);
+
+    // Wait for the clock to pass the cancellation time, after which both the users can withdraw their shares.
+    await new Promise(r => setTimeout(r, 1500));
+
+    await program.rpc.withdraw(
+      {
+        accounts: {
+          channel,
+          treasury,
+          user: wallet.publicKey,
+        },
+        signers: [],
+      },
+    );
+
+    await program.rpc.withdraw(
+      {
+        accounts: {
+          channel,
+          treasury,
+          user: user2.publicKey,
+        },
+        signers: [user2],
+      },
+    );
+
+    channelAccount = await program.account.channel.fetch(channel);
+    assert(channelAccount.balances[0].eq(new BN(0)));
+    assert(channelAccount.balances[1].eq(new BN(0)));
+
+    await program.rpc.closeChannel(
+      {
+        accounts: {
+          channel,
+          treasury,
+          authority: wallet.publicKey,
+        },
+        signers: [],
+      },
+    );
+
+    assert.isNull(await program.account.channel.fetchNullable(channel));
+    assert.equal(await provider.connection.getBalance(treasury), 0);
   });
 });
 
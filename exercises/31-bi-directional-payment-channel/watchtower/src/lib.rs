@@ -0,0 +1,192 @@
+//! Watchtower for the bi-directional payment channel program.
+//!
+//! Users hand over their latest co-signed channel state - a fully signed transaction containing the `challenge_exit`
+//! instructions of both the users - and the watchtower submits it on their behalf if the channel is about to be settled
+//! with an older nonce. Transactions should use a durable transaction nonce so that they stay valid until needed.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use bi_directional_payment_channel::Channel;
+use channel_client::{parse_update_transaction, ClientError};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{pubkey::Pubkey, transaction::Transaction};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum WatchtowerError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid store: {0}")]
+    InvalidStore(#[from] serde_json::Error),
+    #[error("invalid transaction encoding")]
+    InvalidEncoding,
+    #[error("transaction signatures are invalid")]
+    InvalidSignature,
+    #[error(transparent)]
+    InvalidState(#[from] ClientError),
+}
+
+/// A channel state co-signed by both the users.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignedState {
+    #[serde(with = "pubkey_string")]
+    pub channel: Pubkey,
+    #[serde(with = "pubkey_string_pair")]
+    pub users: [Pubkey; 2],
+    pub balances: [u64; 2],
+    pub nonce: u64,
+    #[serde(with = "transaction_base64")]
+    pub transaction: Transaction,
+}
+
+impl SignedState {
+    /// Validates a signed transaction and extracts the channel state it commits.
+    pub fn from_transaction(transaction: Transaction) -> Result<Self, WatchtowerError> {
+        transaction.verify().map_err(|_| WatchtowerError::InvalidSignature)?;
+
+        let (channel, users, update) = parse_update_transaction(&transaction)?;
+        Ok(SignedState {
+            channel,
+            users,
+            balances: update.balances,
+            nonce: update.nonce,
+            transaction,
+        })
+    }
+
+    /// Decodes a base64 encoded, bincode serialized transaction.
+    pub fn from_base64(encoded: &str) -> Result<Self, WatchtowerError> {
+        let bytes = base64::decode(encoded.trim()).map_err(|_| WatchtowerError::InvalidEncoding)?;
+        let transaction = bincode::deserialize(&bytes).map_err(|_| WatchtowerError::InvalidEncoding)?;
+        Self::from_transaction(transaction)
+    }
+
+    fn belongs_to(&self, channel: &Channel) -> bool {
+        self.users.contains(&channel.users[0]) && self.users.contains(&channel.users[1])
+    }
+}
+
+/// The latest co-signed state of every watched channel.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Watchtower {
+    states: Vec<SignedState>,
+}
+
+impl Watchtower {
+    pub fn load(path: &Path) -> Result<Self, WatchtowerError> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), WatchtowerError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn states(&self) -> &[SignedState] {
+        &self.states
+    }
+
+    /// Stores the state if it is newer than the one already stored for the channel. Returns whether it was stored.
+    pub fn add_state(&mut self, state: SignedState) -> bool {
+        match self.states.iter_mut().find(|s| s.channel == state.channel) {
+            Some(existing) if existing.nonce >= state.nonce => false,
+            Some(existing) => {
+                *existing = state;
+                true
+            }
+            None => {
+                self.states.push(state);
+                true
+            }
+        }
+    }
+
+    /// Returns the state to submit if the channel is about to settle with an older nonce than the stored one.
+    ///
+    /// A channel settles once `expires_at` passes, and every accepted `challenge_exit` moves `expires_at` to
+    /// `challenge_period` seconds after it. Submitting early would also shorten the life of the channel, so the
+    /// watchtower only acts once fewer than `margin` seconds are left - by default the challenge period, which means a
+    /// stale close attempt is answered right away.
+    pub fn dispute(
+        &self,
+        channel_key: &Pubkey,
+        channel: &Channel,
+        now: u64,
+        margin: Option<u64>,
+    ) -> Option<&SignedState> {
+        let state = self.states.iter().find(|s| &s.channel == channel_key)?;
+        if state.nonce <= channel.nonce || !state.belongs_to(channel) || channel.expires_at <= now {
+            return None;
+        }
+
+        let margin = margin.unwrap_or(channel.challenge_period);
+        if channel.expires_at - now <= margin {
+            Some(state)
+        } else {
+            None
+        }
+    }
+
+    /// Removes the state of a channel once the chain has caught up with it. Returns whether it was removed.
+    pub fn prune(&mut self, channel_key: &Pubkey, channel: &Channel) -> bool {
+        let len = self.states.len();
+        self.states.retain(|s| &s.channel != channel_key || s.nonce > channel.nonce);
+        self.states.len() != len
+    }
+}
+
+mod pubkey_string {
+    use std::str::FromStr;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use solana_sdk::pubkey::Pubkey;
+
+    pub fn serialize<S: Serializer>(pubkey: &Pubkey, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&pubkey.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pubkey, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Pubkey::from_str(&s).map_err(D::Error::custom)
+    }
+}
+
+mod pubkey_string_pair {
+    use std::str::FromStr;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use solana_sdk::pubkey::Pubkey;
+
+    pub fn serialize<S: Serializer>(pubkeys: &[Pubkey; 2], serializer: S) -> Result<S::Ok, S::Error> {
+        [pubkeys[0].to_string(), pubkeys[1].to_string()].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[Pubkey; 2], D::Error> {
+        let [a, b] = <[String; 2]>::deserialize(deserializer)?;
+        Ok([
+            Pubkey::from_str(&a).map_err(D::Error::custom)?,
+            Pubkey::from_str(&b).map_err(D::Error::custom)?,
+        ])
+    }
+}
+
+mod transaction_base64 {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use solana_sdk::transaction::Transaction;
+
+    pub fn serialize<S: Serializer>(transaction: &Transaction, serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes = bincode::serialize(transaction).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Transaction, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let bytes = base64::decode(s).map_err(D::Error::custom)?;
+        bincode::deserialize(&bytes).map_err(D::Error::custom)
+    }
+}
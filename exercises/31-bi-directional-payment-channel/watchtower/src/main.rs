@@ -0,0 +1,121 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use anchor_lang::AccountDeserialize;
+use bi_directional_payment_channel::Channel;
+use clap::{Parser, Subcommand};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    account::from_account,
+    clock::Clock,
+    commitment_config::CommitmentConfig,
+    sysvar,
+};
+use watchtower::{SignedState, Watchtower};
+
+#[derive(Parser)]
+#[clap(about = "Watchtower for bi-directional payment channels")]
+struct Cli {
+    /// File storing the latest co-signed state of every watched channel.
+    #[clap(long, default_value = "watchtower.json")]
+    store: PathBuf,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Store a co-signed state given as a base64 encoded transaction.
+    Add { transaction: String },
+    /// List the stored states.
+    List,
+    /// Poll the watched channels and submit newer states when needed.
+    Watch {
+        #[clap(long, default_value = "http://localhost:8899")]
+        url: String,
+        /// Seconds between polls.
+        #[clap(long, default_value = "5")]
+        interval: u64,
+        /// Seconds before expiry at which to submit. Defaults to the challenge period of the channel.
+        #[clap(long)]
+        margin: Option<u64>,
+    },
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    let mut watchtower = Watchtower::load(&cli.store)?;
+
+    match cli.command {
+        Command::Add { transaction } => {
+            let state = SignedState::from_base64(&transaction)?;
+            let (channel, nonce) = (state.channel, state.nonce);
+            if watchtower.add_state(state) {
+                watchtower.save(&cli.store)?;
+                println!("Stored state with nonce {} for channel {}", nonce, channel);
+            } else {
+                println!("A state with a higher or equal nonce is already stored for channel {}", channel);
+            }
+        }
+        Command::List => {
+            for state in watchtower.states() {
+                println!(
+                    "{}: nonce {}, balances [{}, {}]",
+                    state.channel, state.nonce, state.balances[0], state.balances[1],
+                );
+            }
+        }
+        Command::Watch { url, interval, margin } => {
+            let rpc = RpcClient::new_with_commitment(url, CommitmentConfig::confirmed());
+            loop {
+                if let Err(err) = poll(&rpc, &mut watchtower, margin) {
+                    eprintln!("Poll failed: {}", err);
+                }
+                watchtower.save(&cli.store)?;
+                thread::sleep(Duration::from_secs(interval));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn poll(rpc: &RpcClient, watchtower: &mut Watchtower, margin: Option<u64>) -> Result<(), Box<dyn Error>> {
+    let clock: Clock = from_account(&rpc.get_account(&sysvar::clock::id())?).ok_or("Invalid clock sysvar")?;
+    let now = clock.unix_timestamp as u64;
+
+    let channel_keys: Vec<_> = watchtower.states().iter().map(|s| s.channel).collect();
+    for channel_key in channel_keys {
+        let account = match rpc.get_account(&channel_key) {
+            Ok(account) => account,
+            Err(err) => {
+                eprintln!("Unable to fetch channel {}: {}", channel_key, err);
+                continue;
+            }
+        };
+        let channel = match Channel::try_deserialize(&mut account.data.as_slice()) {
+            Ok(channel) => channel,
+            Err(err) => {
+                eprintln!("Unable to deserialize channel {}: {}", channel_key, err);
+                continue;
+            }
+        };
+
+        if let Some(state) = watchtower.dispute(&channel_key, &channel, now, margin) {
+            println!(
+                "Channel {} is settling with nonce {}, submitting nonce {}",
+                channel_key, channel.nonce, state.nonce,
+            );
+            match rpc.send_and_confirm_transaction(&state.transaction) {
+                Ok(signature) => println!("Submitted state for channel {}: {}", channel_key, signature),
+                Err(err) => eprintln!("Unable to submit state for channel {}: {}", channel_key, err),
+            }
+        } else if watchtower.prune(&channel_key, &channel) {
+            println!("Channel {} caught up with the stored state", channel_key);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use {
+    anchor_lang::AccountDeserialize,
+    assert_matches::assert_matches,
+    bi_directional_payment_channel::{self, Channel},
+    channel_client::{self, ChannelState, Update},
+    solana_program_test::{processor, tokio, ProgramTest, ProgramTestContext},
+    solana_sdk::{
+        clock::Clock,
+        native_token::LAMPORTS_PER_SOL,
+        pubkey::Pubkey,
+        signature::{Keypair, Signer},
+        transaction::Transaction,
+    },
+    watchtower::{SignedState, Watchtower},
+};
+
+#[tokio::test]
+async fn test_watchtower_disputes_stale_close() {
+    // Scenario: Both users co-sign nonce 1 and later nonce 2 and hand nonce 2 over to the watchtower. One of them then
+    // closes the channel with the stale nonce 1 and the watchtower answers with nonce 2.
+
+    let pt = ProgramTest::new(
+        "bi_directional_payment_channel",
+        bi_directional_payment_channel::id(),
+        processor!(bi_directional_payment_channel::entry),
+    );
+    let mut ctx = pt.start_with_context().await;
+
+    let users = [Keypair::new(), Keypair::new()];
+    let user_keys = [users[0].pubkey(), users[1].pubkey()];
+    let channel = channel_client::channel_address();
+
+    let now = current_time(&mut ctx).await;
+    let instruction = channel_client::initialize(
+        &ctx.payer.pubkey(),
+        user_keys,
+        [LAMPORTS_PER_SOL, 2 * LAMPORTS_PER_SOL],
+        100,
+        now + 1000,
+    );
+    let mut transaction = Transaction::new_with_payer(&[instruction], Some(&ctx.payer.pubkey()));
+    transaction.sign(&[&ctx.payer], ctx.last_blockhash);
+    assert_matches!(ctx.banks_client.process_transaction(transaction).await, Ok(()));
+
+    let state = ChannelState::new(user_keys, [LAMPORTS_PER_SOL, 2 * LAMPORTS_PER_SOL]).unwrap();
+    let stale = co_signed_state(&ctx, &state, &users, state.pay(&user_keys[1], LAMPORTS_PER_SOL / 2).unwrap());
+    let latest = co_signed_state(
+        &ctx,
+        &state,
+        &users,
+        Update { balances: [LAMPORTS_PER_SOL / 2, LAMPORTS_PER_SOL * 5 / 2], nonce: 2 },
+    );
+
+    let mut watchtower = Watchtower::default();
+    let state = SignedState::from_transaction(latest).unwrap();
+    assert_eq!(state.channel, channel);
+    assert_eq!(state.nonce, 2);
+    assert!(watchtower.add_state(state));
+    assert!(!watchtower.add_state(SignedState::from_transaction(stale.clone()).unwrap()));
+
+    // Nothing to do while the channel is far from expiring.
+    let channel_state = fetch_channel(&mut ctx, channel).await;
+    assert!(watchtower.dispute(&channel, &channel_state, now, None).is_none());
+
+    assert_matches!(ctx.banks_client.process_transaction(stale).await, Ok(()));
+    let channel_state = fetch_channel(&mut ctx, channel).await;
+    assert_eq!(channel_state.nonce, 1);
+
+    let now = current_time(&mut ctx).await;
+    let state = watchtower.dispute(&channel, &channel_state, now, None).unwrap();
+    assert_matches!(ctx.banks_client.process_transaction(state.transaction.clone()).await, Ok(()));
+
+    let channel_state = fetch_channel(&mut ctx, channel).await;
+    assert_eq!(channel_state.nonce, 2);
+    assert_eq!(channel_state.balances, [LAMPORTS_PER_SOL / 2, LAMPORTS_PER_SOL * 5 / 2]);
+    assert!(watchtower.prune(&channel, &channel_state));
+    assert!(watchtower.states().is_empty());
+}
+
+fn co_signed_state(
+    ctx: &ProgramTestContext,
+    state: &ChannelState,
+    users: &[Keypair; 2],
+    update: Update,
+) -> Transaction {
+    let mut transaction = state.update_transaction(&update, &ctx.payer.pubkey()).unwrap();
+    transaction.partial_sign(&[&ctx.payer], ctx.last_blockhash);
+    transaction.partial_sign(&[&users[0]], ctx.last_blockhash);
+    transaction.partial_sign(&[&users[1]], ctx.last_blockhash);
+    transaction
+}
+
+async fn current_time(ctx: &mut ProgramTestContext) -> u64 {
+    let clock: Clock = ctx.banks_client.get_sysvar().await.unwrap();
+    clock.unix_timestamp as u64
+}
+
+async fn fetch_channel(ctx: &mut ProgramTestContext, channel: Pubkey) -> Channel {
+    let account = ctx.banks_client.get_account(channel).await.unwrap().unwrap();
+    Channel::try_deserialize(&mut account.data.as_slice()).unwrap()
+}
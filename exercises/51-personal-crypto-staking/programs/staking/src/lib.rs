@@ -1,59 +1,761 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{transfer, Mint, Token, TokenAccount, Transfer};
+use anchor_lang::solana_program::{
+    instruction::Instruction,
+    program::{invoke_signed, set_return_data},
+    program_pack::Pack,
+    system_instruction,
+};
+use anchor_spl::token::{close_account, transfer, CloseAccount, Mint, Token, TokenAccount, Transfer};
+use mpl_token_metadata::state::Metadata;
+use spl_token_2022::extension::{ExtensionType, StateWithExtensions};
 
 declare_id!("9RcRcEXKMpNJ5zMaUbTwqKoh2RoehEvd9csQAoBz4MCo");
 
 pub const ESCROW_ACCOUNT_SEED: &[u8] = b"escrow";
+pub const ESCROW_TOKEN_ACCOUNT_SEED: &[u8] = b"escrow_token";
+pub const REWARD_TOKEN_ACCOUNT_SEED: &[u8] = b"reward_token";
 pub const STAKE_ACCOUNT_SEED: &[u8] = b"stake";
+pub const STAKE_TOKEN_ACCOUNT_SEED: &[u8] = b"stake_token";
+pub const POSITIONS_ACCOUNT_SEED: &[u8] = b"positions";
+pub const SHARE_MINT_SEED: &[u8] = b"share_mint";
+pub const VAULT_STAKE_ACCOUNT_SEED: &[u8] = b"vault_stake";
 
-// Reward ratio per 5 second =
-//     REWARD_RATIO_PER_5_SECONDS_SIGNIFICAND * 10 ^ -REWARD_RATIO_PER_5_SECONDS_DECIMAL
-const REWARD_RATIO_PER_5_SECONDS_SIGNIFICAND: u128 = 2;
-const REWARD_RATIO_PER_5_SECONDS_DECIMAL: u32 = 8;
-const REWARD_RATIO_PER_5_SECONDS_DIVISOR: u128 = 10u128.pow(REWARD_RATIO_PER_5_SECONDS_DECIMAL);
+pub const LOCK_TIER_COUNT: usize = 4;
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+const DAY: u64 = 24 * 60 * 60;
+// Approximate slot and epoch durations, used to convert the default lock tiers for pools not using timestamps.
+const SLOT_DURATION_MS: u64 = 400;
+const SLOTS_PER_EPOCH: u64 = 432_000;
+
+// Reward rate and reward per token are scaled by REWARD_PRECISION.
+const REWARD_PRECISION: u128 = 10u128.pow(12);
 
 #[program]
 pub mod staking {
     use super::*;
 
-    pub fn create_escrow_account(ctx: Context<CreateEscrowAccount>) -> Result<()> {
+    pub fn create_escrow_account(ctx: Context<CreateEscrowAccount>, time_mode: TimeMode) -> Result<()> {
+        // Both the staked and the reward tokens are transferred with the token program of the pool.
+        let accounts = &ctx.accounts;
+        let token_program = accounts.token_program.key();
+        unpack_mint(&accounts.mint)?;
+        unpack_mint(&accounts.reward_mint)?;
+        require!(
+            *accounts.mint.owner == token_program && *accounts.reward_mint.owner == token_program,
+            StakingError::InvalidMint
+        );
+
+        let escrow_key = accounts.escrow.key();
+        create_token_account(
+            &accounts.token_program,
+            &accounts.payer,
+            &accounts.escrow_token,
+            &accounts.mint,
+            &escrow_key,
+            &[ESCROW_TOKEN_ACCOUNT_SEED, escrow_key.as_ref(), &[*ctx.bumps.get("escrow_token").unwrap()]],
+            &accounts.system_program.to_account_info(),
+        )?;
+        create_token_account(
+            &accounts.token_program,
+            &accounts.payer,
+            &accounts.reward_token,
+            &accounts.reward_mint,
+            &escrow_key,
+            &[REWARD_TOKEN_ACCOUNT_SEED, escrow_key.as_ref(), &[*ctx.bumps.get("reward_token").unwrap()]],
+            &accounts.system_program.to_account_info(),
+        )?;
+
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.init(
+            PoolKind::Fungible,
+            time_mode,
+            ctx.accounts.payer.key(),
+            ctx.accounts.mint.key(),
+            *ctx.bumps.get("escrow").unwrap(),
+            ctx.accounts.reward_mint.key(),
+            ctx.accounts.reward_token.key(),
+        )?;
+        escrow.token_program = token_program;
+        escrow.escrow_token = ctx.accounts.escrow_token.key();
+        Ok(())
+    }
+
+    pub fn create_nft_escrow_account(ctx: Context<CreateNftEscrowAccount>, time_mode: TimeMode) -> Result<()> {
+        // Staked NFTs are held in a token account per stake, so the pool has no escrow_token.
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.init(
+            PoolKind::Nft,
+            time_mode,
+            ctx.accounts.payer.key(),
+            ctx.accounts.mint.key(),
+            *ctx.bumps.get("escrow").unwrap(),
+            ctx.accounts.reward_mint.key(),
+            ctx.accounts.reward_token.key(),
+        )?;
+        escrow.token_program = ctx.accounts.token_program.key();
+        Ok(())
+    }
+
+    pub fn update_lock_tiers(
+        ctx: Context<UpdateEscrowAccount>,
+        lock_tiers: [LockTier; LOCK_TIER_COUNT],
+        early_withdrawal_penalty_bps: u16,
+    ) -> Result<()> {
+        for tier in lock_tiers.iter() {
+            require!(tier.multiplier_bps > 0, StakingError::InvalidLockTier);
+        }
+        require!(
+            early_withdrawal_penalty_bps as u64 <= BPS_DENOMINATOR,
+            StakingError::InvalidPenalty
+        );
+        let escrow = &mut ctx.accounts.escrow;
+        // Penalties are redistributed as rewards, which is only possible when rewards are paid in the staked token.
+        require!(
+            early_withdrawal_penalty_bps == 0
+                || (escrow.kind == PoolKind::Fungible && escrow.reward_mint == escrow.mint),
+            StakingError::InvalidPenalty
+        );
+
+        // Existing stakes keep the terms they were created with.
+        escrow.lock_tiers = lock_tiers;
+        escrow.early_withdrawal_penalty_bps = early_withdrawal_penalty_bps;
+        Ok(())
+    }
+
+    pub fn update_unbonding_period(ctx: Context<UpdateEscrowAccount>, unbonding_period: u64) -> Result<()> {
+        // Stakes already unbonding keep the end of unbonding they were given.
+        ctx.accounts.escrow.unbonding_period = unbonding_period;
+        Ok(())
+    }
+
+    pub fn set_paused(ctx: Context<UpdateEscrowAccount>, paused: bool) -> Result<()> {
+        // Pausing only stops new stakes, existing stakes keep earning rewards and can be withdrawn.
+        ctx.accounts.escrow.paused = paused;
+        Ok(())
+    }
+
+    pub fn enable_emergency(ctx: Context<UpdateEscrowAccount>) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        require!(!escrow.emergency, StakingError::Emergency);
+
+        // Rewards stop being emitted and the stakes forfeit their rewards, so that the reward vault can be withdrawn
+        // entirely by the payer while the stakes withdraw their principal with emergency_withdraw.
+        let now = escrow.now()?;
+        escrow.update_reward(now)?;
+        escrow.reward_end_at = escrow.reward_end_at.min(now);
+        escrow.paused = true;
+        escrow.emergency = true;
+        Ok(())
+    }
+
+    pub fn transfer_authority(ctx: Context<UpdateEscrowAccount>, new_payer: Pubkey) -> Result<()> {
+        // The new payer has to accept the authority, so that it cannot be transferred to an account nobody controls.
+        ctx.accounts.escrow.pending_payer = new_payer;
+        Ok(())
+    }
+
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.payer = escrow.pending_payer;
+        escrow.pending_payer = Pubkey::default();
+        Ok(())
+    }
+
+    pub fn withdraw_rewards(ctx: Context<WithdrawRewards>, amount: u64) -> Result<()> {
+        require!(amount > 0, StakingError::ZeroAmount);
+
         let escrow = &mut ctx.accounts.escrow;
-        escrow.payer = ctx.accounts.payer.key();
-        escrow.mint = ctx.accounts.mint.key();
-        escrow.bump = *ctx.bumps.get("escrow").unwrap();
+        if escrow.emergency {
+            require!(
+                token_amount(&ctx.accounts.reward_token)? >= amount,
+                StakingError::InsufficientFunds
+            );
+        } else {
+            let now = escrow.now()?;
+            escrow.remove_reward(amount, now)?;
+        }
+
+        transfer_from_escrow(
+            &ctx.accounts.token_program,
+            &ctx.accounts.escrow,
+            &ctx.accounts.reward_token,
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.payer_token,
+            amount,
+        )?;
         Ok(())
     }
 
-    pub fn fund_escrow_account(ctx: Context<FundEscrowAccount>, amount: u64) -> Result<()> {
+    pub fn fund_escrow_account(ctx: Context<FundEscrowAccount>, amount: u64, duration: u64) -> Result<()> {
+        require!(duration > 0, StakingError::InvalidRewardDuration);
         require!(
-            ctx.accounts.payer_token.amount >= amount,
+            token_amount(&ctx.accounts.payer_token)? >= amount,
             StakingError::InsufficientFunds
         );
-        ctx.accounts.transfer_to_escrow(amount)
+
+        // Only the amount received by reward_token is emitted, which is lower than amount for mints charging
+        // transfer fees.
+        let amount = ctx.accounts.transfer_to_reward_token(amount)?;
+        let escrow = &mut ctx.accounts.escrow;
+        let now = escrow.now()?;
+        escrow.add_reward(amount, duration, now)
+    }
+
+    pub fn update_reward_rate(ctx: Context<UpdateEscrowAccount>, reward_rate: u64) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        require!(!escrow.emergency, StakingError::Emergency);
+
+        // Rewards emitted so far are accrued with the old rate.
+        let now = escrow.now()?;
+        escrow.set_reward_rate(reward_rate, now)
+    }
+
+    pub fn create_positions_account(ctx: Context<CreatePositionsAccount>) -> Result<()> {
+        let positions = &mut ctx.accounts.positions;
+        positions.owner = ctx.accounts.owner.key();
+        positions.mint = ctx.accounts.mint.key();
+        positions.count = 0;
+        positions.bump = *ctx.bumps.get("positions").unwrap();
+        Ok(())
     }
 
-    pub fn create_stake_account(ctx: Context<CreateStakeAccount>, amount: u64) -> Result<()> {
+    pub fn create_stake_account(ctx: Context<CreateStakeAccount>, amount: u64, lock_tier: u8) -> Result<()> {
         require!(amount > 0, StakingError::ZeroAmount);
         require!(
-            ctx.accounts.owner_token.amount >= amount,
+            (lock_tier as usize) < LOCK_TIER_COUNT,
+            StakingError::InvalidLockTier
+        );
+        require!(
+            token_amount(&ctx.accounts.owner_token)? >= amount,
             StakingError::InsufficientFunds
         );
 
+        // Only the amount received by escrow_token is staked, which is lower than amount for mints charging transfer
+        // fees.
+        let amount = ctx.accounts.transfer_to_escrow(amount)?;
+        require!(amount > 0, StakingError::ZeroAmount);
+
+        let now = ctx.accounts.escrow.now()?;
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.update_reward(now)?;
+        let tier = escrow.lock_tiers[lock_tier as usize];
+
+        let positions = &mut ctx.accounts.positions;
+        let index = positions.count;
+        positions.count = positions.count
+            .checked_add(1)
+            .ok_or(error!(StakingError::PositionsOutOfBounds))?;
+
+        let stake = &mut ctx.accounts.stake;
+        stake.owner = ctx.accounts.owner.key();
+        stake.mint = ctx.accounts.mint.key();
+        stake.index = index;
+        stake.amount = 0;
+        stake.created_at = now;
+        stake.bump = *ctx.bumps.get("stake").unwrap();
+        stake.reward_per_token_paid = escrow.reward_per_token;
+        stake.reward = 0;
+        stake.unlock_at = now
+            .checked_add(tier.duration)
+            .ok_or(error!(StakingError::InvalidLockTier))?;
+        stake.multiplier_bps = tier.multiplier_bps;
+        stake.weight = 0;
+        stake.unbonding_amount = 0;
+        stake.unbonding_end_at = 0;
+        stake.nft_mint = Pubkey::default();
+        stake.stake_token = Pubkey::default();
+        stake.set_amount(escrow, amount)
+    }
+
+    pub fn create_nft_stake_account(ctx: Context<CreateNftStakeAccount>, lock_tier: u8) -> Result<()> {
+        require!(
+            (lock_tier as usize) < LOCK_TIER_COUNT,
+            StakingError::InvalidLockTier
+        );
+        verify_collection(
+            &ctx.accounts.nft_metadata,
+            &ctx.accounts.nft_mint.key(),
+            &ctx.accounts.mint.key(),
+        )?;
+
+        let now = ctx.accounts.escrow.now()?;
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.update_reward(now)?;
+        let tier = escrow.lock_tiers[lock_tier as usize];
+
+        let positions = &mut ctx.accounts.positions;
+        let index = positions.count;
+        positions.count = positions.count
+            .checked_add(1)
+            .ok_or(error!(StakingError::PositionsOutOfBounds))?;
+
         let stake = &mut ctx.accounts.stake;
         stake.owner = ctx.accounts.owner.key();
         stake.mint = ctx.accounts.mint.key();
-        stake.amount = amount;
-        stake.created_at = get_current_time()?;
+        stake.index = index;
+        stake.amount = 0;
+        stake.created_at = now;
         stake.bump = *ctx.bumps.get("stake").unwrap();
+        stake.reward_per_token_paid = escrow.reward_per_token;
+        stake.reward = 0;
+        stake.unlock_at = now
+            .checked_add(tier.duration)
+            .ok_or(error!(StakingError::InvalidLockTier))?;
+        stake.multiplier_bps = tier.multiplier_bps;
+        stake.weight = 0;
+        stake.unbonding_amount = 0;
+        stake.unbonding_end_at = 0;
+        stake.nft_mint = ctx.accounts.nft_mint.key();
+        stake.stake_token = ctx.accounts.stake_token.key();
+        stake.set_amount(escrow, 1)?;
+
+        ctx.accounts.transfer_to_stake_token()
+    }
+
+    pub fn add_stake(ctx: Context<AddStake>, amount: u64) -> Result<()> {
+        require!(amount > 0, StakingError::ZeroAmount);
+        require!(
+            token_amount(&ctx.accounts.owner_token)? >= amount,
+            StakingError::InsufficientFunds
+        );
+
+        let amount = ctx.accounts.transfer_to_escrow(amount)?;
+        require!(amount > 0, StakingError::ZeroAmount);
+
+        let escrow = &mut ctx.accounts.escrow;
+        let now = escrow.now()?;
+        escrow.update_reward(now)?;
+
+        let stake = &mut ctx.accounts.stake;
+        stake.update_reward(escrow)?;
+        let new_amount = stake.amount
+            .checked_add(amount)
+            .ok_or(error!(StakingError::TotalAmountOutOfBounds))?;
+        stake.set_amount(escrow, new_amount)
+    }
+
+    pub fn partial_unstake(ctx: Context<PartialUnstake>, amount: u64) -> Result<()> {
+        require!(amount > 0, StakingError::ZeroAmount);
+        require!(
+            ctx.accounts.stake.amount >= amount,
+            StakingError::InsufficientStake
+        );
+
+        let now = ctx.accounts.escrow.now()?;
+        require!(now >= ctx.accounts.stake.unlock_at, StakingError::Locked);
+        require!(
+            ctx.accounts.escrow.unbonding_period == 0,
+            StakingError::UnbondingRequired
+        );
+
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.update_reward(now)?;
+
+        let stake = &mut ctx.accounts.stake;
+        stake.update_reward(escrow)?;
+        let new_amount = stake.amount - amount;
+        stake.set_amount(escrow, new_amount)?;
+
+        transfer_from_escrow(
+            &ctx.accounts.token_program,
+            &ctx.accounts.escrow,
+            &ctx.accounts.escrow_token,
+            &ctx.accounts.mint,
+            &ctx.accounts.owner_token,
+            amount,
+        )?;
+        Ok(())
+    }
+
+    pub fn request_unstake(ctx: Context<RequestUnstake>, amount: u64) -> Result<()> {
+        require!(amount > 0, StakingError::ZeroAmount);
+        // A new request would push back the end of unbonding of the amount already unbonding, so it has to be
+        // completed first.
+        require!(ctx.accounts.stake.unbonding_amount == 0, StakingError::Unbonding);
+        require!(
+            ctx.accounts.stake.amount >= amount,
+            StakingError::InsufficientStake
+        );
+
+        let now = ctx.accounts.escrow.now()?;
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.update_reward(now)?;
+
+        // The unbonding amount stops accruing rewards right away.
+        let stake = &mut ctx.accounts.stake;
+        stake.update_reward(escrow)?;
+        let penalty = stake.calculate_early_withdrawal_penalty(escrow, amount, now)?;
+        let new_amount = stake.amount - amount;
+        stake.set_amount(escrow, new_amount)?;
+
+        let unbonding_amount = amount - penalty;
+        stake.unbonding_amount = stake.unbonding_amount
+            .checked_add(unbonding_amount)
+            .ok_or(error!(StakingError::TotalAmountOutOfBounds))?;
+        stake.unbonding_end_at = now
+            .checked_add(escrow.unbonding_period)
+            .ok_or(error!(StakingError::TotalAmountOutOfBounds))?;
+        escrow.total_unbonding = escrow.total_unbonding
+            .checked_add(unbonding_amount)
+            .ok_or(error!(StakingError::TotalAmountOutOfBounds))?;
+
+        if penalty > 0 {
+            // Only the amount received by reward_token is distributed, net of any transfer fee.
+            let penalty = transfer_from_escrow(
+                &ctx.accounts.token_program,
+                &ctx.accounts.escrow,
+                &ctx.accounts.escrow_token,
+                &ctx.accounts.mint,
+                &ctx.accounts.reward_token,
+                penalty,
+            )?;
+            ctx.accounts.escrow.distribute_penalty(penalty, now)?;
+        }
+        Ok(())
+    }
+
+    pub fn complete_unstake(ctx: Context<CompleteUnstake>) -> Result<()> {
+        let amount = ctx.accounts.stake.unbonding_amount;
+        require!(amount > 0, StakingError::ZeroAmount);
+        require!(
+            ctx.accounts.escrow.now()? >= ctx.accounts.stake.unbonding_end_at,
+            StakingError::Unbonding
+        );
+
+        ctx.accounts.stake.unbonding_amount = 0;
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.total_unbonding -= amount;
+
+        transfer_from_escrow(
+            &ctx.accounts.token_program,
+            &ctx.accounts.escrow,
+            &ctx.accounts.escrow_token,
+            &ctx.accounts.mint,
+            &ctx.accounts.owner_token,
+            amount,
+        )?;
+        Ok(())
+    }
 
-        ctx.accounts.transfer_to_escrow(amount)
+    pub fn emergency_withdraw(ctx: Context<EmergencyWithdraw>) -> Result<()> {
+        // Returns the principal, including the unbonding amount, regardless of locks and unbonding periods.
+        let escrow = &mut ctx.accounts.escrow;
+        let stake = &mut ctx.accounts.stake;
+        let amount = stake.amount
+            .checked_add(stake.unbonding_amount)
+            .ok_or(error!(StakingError::TotalAmountOutOfBounds))?;
+        escrow.total_unbonding -= stake.unbonding_amount;
+        stake.unbonding_amount = 0;
+        stake.set_amount(escrow, 0)?;
+
+        if amount > 0 {
+            transfer_from_escrow(
+                &ctx.accounts.token_program,
+                &ctx.accounts.escrow,
+                &ctx.accounts.escrow_token,
+                &ctx.accounts.mint,
+                &ctx.accounts.owner_token,
+                amount,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
+        let escrow = &mut ctx.accounts.escrow;
+        let now = escrow.now()?;
+        escrow.update_reward(now)?;
+
+        let stake = &mut ctx.accounts.stake;
+        stake.update_reward(escrow)?;
+        let reward = stake.reward;
+        stake.reward = 0;
+
+        if reward > 0 {
+            transfer_from_escrow(
+                &ctx.accounts.token_program,
+                &ctx.accounts.escrow,
+                &ctx.accounts.reward_token,
+                &ctx.accounts.reward_mint,
+                &ctx.accounts.owner_reward_token,
+                reward,
+            )?;
+        }
+        Ok(())
     }
 
     pub fn close_stake_account(ctx: Context<CloseStakeAccount>) -> Result<()> {
-        ctx.accounts.transfer_from_escrow(
-            ctx.accounts.escrow.bump,
-            ctx.accounts.stake.calculate_total_amount()?,
-        )
+        require!(
+            ctx.accounts.stake.unbonding_amount == 0,
+            StakingError::Unbonding
+        );
+        require!(
+            ctx.accounts.stake.amount == 0 || ctx.accounts.escrow.unbonding_period == 0,
+            StakingError::UnbondingRequired
+        );
+
+        let now = ctx.accounts.escrow.now()?;
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.update_reward(now)?;
+
+        let stake = &mut ctx.accounts.stake;
+        stake.update_reward(escrow)?;
+        let reward = stake.reward;
+        let penalty = stake.calculate_early_withdrawal_penalty(escrow, stake.amount, now)?;
+        let amount = stake.amount - penalty;
+        stake.set_amount(escrow, 0)?;
+
+        // The stake is closed entirely or not at all, so it is rejected if the vaults cannot cover it.
+        let accounts = &ctx.accounts;
+        require!(
+            token_amount(&accounts.escrow_token)? >= amount + penalty
+                && token_amount(&accounts.reward_token)? >= reward,
+            StakingError::InsufficientEscrow
+        );
+        if amount > 0 {
+            transfer_from_escrow(
+                &accounts.token_program,
+                &accounts.escrow,
+                &accounts.escrow_token,
+                &accounts.mint,
+                &accounts.owner_token,
+                amount,
+            )?;
+        }
+        if reward > 0 {
+            transfer_from_escrow(
+                &accounts.token_program,
+                &accounts.escrow,
+                &accounts.reward_token,
+                &accounts.reward_mint,
+                &accounts.owner_reward_token,
+                reward,
+            )?;
+        }
+        if penalty > 0 {
+            let penalty = transfer_from_escrow(
+                &accounts.token_program,
+                &accounts.escrow,
+                &accounts.escrow_token,
+                &accounts.mint,
+                &accounts.reward_token,
+                penalty,
+            )?;
+            // The penalty is shared by the remaining stakes, so it is distributed only after this stake is removed.
+            ctx.accounts.escrow.distribute_penalty(penalty, now)?;
+        }
+        Ok(())
+    }
+
+    pub fn close_nft_stake_account(ctx: Context<CloseNftStakeAccount>) -> Result<()> {
+        // A staked NFT cannot be partially withheld, so locked NFT stakes cannot be closed early, except in an
+        // emergency where the NFT is returned without rewards.
+        let now = ctx.accounts.escrow.now()?;
+        let emergency = ctx.accounts.escrow.emergency;
+        require!(
+            emergency || now >= ctx.accounts.stake.unlock_at,
+            StakingError::Locked
+        );
+
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.update_reward(now)?;
+
+        let stake = &mut ctx.accounts.stake;
+        stake.update_reward(escrow)?;
+        let reward = if emergency { 0 } else { stake.reward };
+        stake.set_amount(escrow, 0)?;
+
+        let accounts = &ctx.accounts;
+        let token_program = accounts.token_program.to_account_info();
+        transfer_from_escrow(
+            &token_program,
+            &accounts.escrow,
+            &accounts.stake_token.to_account_info(),
+            &accounts.nft_mint.to_account_info(),
+            &accounts.owner_nft_token.to_account_info(),
+            1,
+        )?;
+        close_escrow_token_account(
+            &accounts.token_program,
+            &accounts.escrow,
+            &accounts.stake_token,
+            accounts.owner.to_account_info(),
+        )?;
+        if reward > 0 {
+            transfer_from_escrow(
+                &token_program,
+                &accounts.escrow,
+                &accounts.reward_token.to_account_info(),
+                &accounts.reward_mint.to_account_info(),
+                &accounts.owner_reward_token.to_account_info(),
+                reward,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn view_position(ctx: Context<ViewPosition>) -> Result<()> {
+        // Read-only, meant to be simulated to get the current state of a stake without modifying it.
+        let escrow = &ctx.accounts.escrow;
+        let stake = &ctx.accounts.stake;
+        let reward = if escrow.emergency {
+            0
+        } else {
+            stake.calculate_reward(escrow, escrow.now()?)?
+        };
+        let view = PositionView {
+            amount: stake.amount,
+            reward,
+            unlock_at: stake.unlock_at,
+            unbonding_amount: stake.unbonding_amount,
+            unbonding_end_at: stake.unbonding_end_at,
+        };
+        set_return_data(&view.try_to_vec()?);
+        Ok(())
+    }
+
+    pub fn create_vault(ctx: Context<CreateVault>) -> Result<()> {
+        let accounts = &ctx.accounts;
+        // Rewards are compounded into the stake of the vault, so they have to be paid in the staked token.
+        require!(
+            accounts.escrow.kind == PoolKind::Fungible && accounts.escrow.reward_mint == accounts.escrow.mint,
+            StakingError::InvalidVault
+        );
+
+        // Shares are minted with the token program of the pool, with the decimals of the staked token.
+        let escrow_key = accounts.escrow.key();
+        create_mint(
+            &accounts.token_program,
+            &accounts.payer,
+            &accounts.share_mint,
+            unpack_mint(&accounts.mint)?.decimals,
+            &escrow_key,
+            &[SHARE_MINT_SEED, escrow_key.as_ref(), &[*ctx.bumps.get("share_mint").unwrap()]],
+            &accounts.system_program.to_account_info(),
+        )?;
+
+        let escrow = &mut ctx.accounts.escrow;
+        escrow.share_mint = ctx.accounts.share_mint.key();
+
+        // The vault stakes like any other stake, without lock and owned by the escrow.
+        let now = escrow.now()?;
+        let vault_stake = &mut ctx.accounts.vault_stake;
+        vault_stake.owner = escrow.key();
+        vault_stake.mint = escrow.mint;
+        vault_stake.index = 0;
+        vault_stake.amount = 0;
+        vault_stake.created_at = now;
+        vault_stake.bump = *ctx.bumps.get("vault_stake").unwrap();
+        vault_stake.reward_per_token_paid = escrow.reward_per_token;
+        vault_stake.reward = 0;
+        vault_stake.unlock_at = now;
+        vault_stake.multiplier_bps = BPS_DENOMINATOR as u16;
+        vault_stake.weight = 0;
+        vault_stake.unbonding_amount = 0;
+        vault_stake.unbonding_end_at = 0;
+        vault_stake.nft_mint = Pubkey::default();
+        vault_stake.stake_token = Pubkey::default();
+        Ok(())
+    }
+
+    pub fn deposit_vault(ctx: Context<DepositVault>, amount: u64) -> Result<()> {
+        require!(amount > 0, StakingError::ZeroAmount);
+        // Vault shares are withdrawn at once, so they cannot go through the unbonding of the stakes.
+        require!(
+            ctx.accounts.escrow.unbonding_period == 0,
+            StakingError::UnbondingRequired
+        );
+        require!(
+            token_amount(&ctx.accounts.owner_token)? >= amount,
+            StakingError::InsufficientFunds
+        );
+
+        ctx.accounts.compound()?;
+        let amount = ctx.accounts.transfer_to_escrow(amount)?;
+
+        let supply = unpack_mint(&ctx.accounts.share_mint)?.supply;
+        let leftover = ctx.accounts.vault_stake.amount;
+        if supply == 0 && leftover > 0 {
+            // Assets left in the vault without any shares, such as rewards compounded after the last withdrawal,
+            // belong to no depositor. They are shared with the stakes of the pool rather than given to this deposit.
+            let escrow = &mut ctx.accounts.escrow;
+            let now = escrow.now()?;
+            ctx.accounts.vault_stake.set_amount(escrow, 0)?;
+            let leftover = transfer_from_escrow(
+                &ctx.accounts.token_program,
+                &ctx.accounts.escrow,
+                &ctx.accounts.escrow_token,
+                &ctx.accounts.mint,
+                &ctx.accounts.reward_token,
+                leftover,
+            )?;
+            ctx.accounts.escrow.distribute_penalty(leftover, now)?;
+            ctx.accounts.vault_stake.update_reward(&ctx.accounts.escrow)?;
+        }
+
+        // Shares are minted at the current share price, rounded down in favour of the vault.
+        let assets = ctx.accounts.vault_stake.amount;
+        let shares = if supply == 0 {
+            amount
+        } else {
+            require!(assets > 0, StakingError::InvalidVault);
+            let shares = amount as u128 * supply as u128 / assets as u128;
+            if shares > u64::MAX as u128 {
+                return err!(StakingError::TotalAmountOutOfBounds);
+            }
+            shares as u64
+        };
+        require!(shares > 0, StakingError::ZeroAmount);
+
+        let escrow = &mut ctx.accounts.escrow;
+        let vault_stake = &mut ctx.accounts.vault_stake;
+        let new_amount = assets
+            .checked_add(amount)
+            .ok_or(error!(StakingError::TotalAmountOutOfBounds))?;
+        vault_stake.set_amount(escrow, new_amount)?;
+
+        ctx.accounts.mint_shares(shares)
+    }
+
+    pub fn withdraw_vault(ctx: Context<WithdrawVault>, shares: u64) -> Result<()> {
+        require!(shares > 0, StakingError::ZeroAmount);
+        require!(
+            token_amount(&ctx.accounts.owner_share_token)? >= shares,
+            StakingError::InsufficientFunds
+        );
+
+        // Like the other stakes, the vault only skips the unbonding in an emergency.
+        require!(
+            ctx.accounts.escrow.unbonding_period == 0 || ctx.accounts.escrow.emergency,
+            StakingError::UnbondingRequired
+        );
+
+        // Rewards are forfeited in an emergency, so only the principal compounded so far is withdrawn.
+        if !ctx.accounts.escrow.emergency {
+            ctx.accounts.compound()?;
+        }
+
+        let assets = ctx.accounts.vault_stake.amount;
+        let supply = unpack_mint(&ctx.accounts.share_mint)?.supply;
+        let amount = (shares as u128 * assets as u128 / supply as u128) as u64;
+
+        let escrow = &mut ctx.accounts.escrow;
+        let vault_stake = &mut ctx.accounts.vault_stake;
+        vault_stake.set_amount(escrow, assets - amount)?;
+
+        ctx.accounts.burn_shares(shares)?;
+        if amount > 0 {
+            transfer_from_escrow(
+                &ctx.accounts.token_program,
+                &ctx.accounts.escrow,
+                &ctx.accounts.escrow_token,
+                &ctx.accounts.mint,
+                &ctx.accounts.owner_token,
+                amount,
+            )?;
+        }
+        Ok(())
     }
 }
 
@@ -66,189 +768,1558 @@ pub struct CreateEscrowAccount<'info> {
             ESCROW_ACCOUNT_SEED,
             mint.key().as_ref(),
         ],
-        bump,
+        bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: Verified to be a mint of token_program in the instruction.
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a mint of token_program in the instruction.
+    pub reward_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Created in the instruction.
+    #[account(
+        mut,
+        seeds = [
+            ESCROW_TOKEN_ACCOUNT_SEED,
+            escrow.key().as_ref(),
+        ],
+        bump,
+    )]
+    pub escrow_token: UncheckedAccount<'info>,
+    /// CHECK: Created in the instruction.
+    #[account(
+        mut,
+        seeds = [
+            REWARD_TOKEN_ACCOUNT_SEED,
+            escrow.key().as_ref(),
+        ],
+        bump,
+    )]
+    pub reward_token: UncheckedAccount<'info>,
+
+    /// CHECK: Either the token program or the token-2022 program.
+    #[account(constraint = is_token_program(token_program.key) @ StakingError::InvalidTokenProgram)]
+    pub token_program: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateNftEscrowAccount<'info> {
+    #[account(
+        init,
+        payer = payer,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    // Mint of the collection NFT.
+    pub mint: Account<'info, Mint>,
+    pub reward_mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = payer,
+        seeds = [
+            REWARD_TOKEN_ACCOUNT_SEED,
+            escrow.key().as_ref(),
+        ],
+        bump,
+        token::mint = reward_mint,
+        token::authority = escrow,
+    )]
+    pub reward_token: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct FundEscrowAccount<'info> {
+    #[account(
+        mut,
+        constraint = !escrow.emergency @ StakingError::Emergency,
+        has_one = payer,
+        has_one = reward_mint,
+        has_one = reward_token,
+        has_one = token_program,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: Verified by the escrow.
+    pub reward_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub reward_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of reward_mint owned by payer.
+    #[account(
+        mut,
+        constraint = is_token_account(&payer_token, token_program.key, reward_mint.key, Some(payer.key))
+            @ StakingError::InvalidTokenAccount,
+    )]
+    pub payer_token: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateEscrowAccount<'info> {
+    #[account(
+        mut,
+        has_one = payer,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    pub payer: Signer<'info>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAuthority<'info> {
+    #[account(
+        mut,
+        constraint = escrow.pending_payer == new_payer.key() @ StakingError::InvalidAuthority,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    pub new_payer: Signer<'info>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawRewards<'info> {
+    #[account(
+        mut,
+        has_one = payer,
+        has_one = reward_mint,
+        has_one = reward_token,
+        has_one = token_program,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    pub payer: Signer<'info>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: Verified by the escrow.
+    pub reward_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub reward_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of reward_mint owned by payer.
+    #[account(
+        mut,
+        constraint = is_token_account(&payer_token, token_program.key, reward_mint.key, Some(payer.key))
+            @ StakingError::InvalidTokenAccount,
+    )]
+    pub payer_token: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CreatePositionsAccount<'info> {
+    #[account(
+        init,
+        payer = owner,
+        seeds = [
+            POSITIONS_ACCOUNT_SEED,
+            owner.key.as_ref(),
+            mint.key().as_ref(),
+        ],
+        bump,
+    )]
+    pub positions: Account<'info, Positions>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    /// CHECK: Only used as a seed, stakes are verified against the escrow of the mint.
+    pub mint: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateStakeAccount<'info> {
+    #[account(
+        mut,
+        has_one = owner,
+        has_one = mint,
+        seeds = [
+            POSITIONS_ACCOUNT_SEED,
+            owner.key.as_ref(),
+            mint.key().as_ref(),
+        ],
+        bump = positions.bump,
+    )]
+    pub positions: Account<'info, Positions>,
+    #[account(
+        init,
+        payer = owner,
+        seeds = [
+            STAKE_ACCOUNT_SEED,
+            owner.key.as_ref(),
+            mint.key().as_ref(),
+            &positions.count.to_le_bytes(),
+        ],
+        bump,
+    )]
+    pub stake: Account<'info, Stake>,
+    #[account(
+        mut,
+        constraint = !escrow.paused && !escrow.emergency @ StakingError::Paused,
+        has_one = escrow_token,
+        has_one = token_program,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub escrow_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of mint owned by owner.
+    #[account(
+        mut,
+        constraint = is_token_account(&owner_token, token_program.key, mint.key, Some(owner.key))
+            @ StakingError::InvalidTokenAccount,
+    )]
+    pub owner_token: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    pub token_program: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateNftStakeAccount<'info> {
+    #[account(
+        mut,
+        has_one = owner,
+        has_one = mint,
+        seeds = [
+            POSITIONS_ACCOUNT_SEED,
+            owner.key.as_ref(),
+            mint.key().as_ref(),
+        ],
+        bump = positions.bump,
+    )]
+    pub positions: Account<'info, Positions>,
+    #[account(
+        init,
+        payer = owner,
+        seeds = [
+            STAKE_ACCOUNT_SEED,
+            owner.key.as_ref(),
+            mint.key().as_ref(),
+            &positions.count.to_le_bytes(),
+        ],
+        bump,
+    )]
+    pub stake: Account<'info, Stake>,
+    #[account(
+        mut,
+        constraint = !escrow.paused && !escrow.emergency @ StakingError::Paused,
+        constraint = escrow.kind == PoolKind::Nft @ StakingError::InvalidPoolKind,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    // Mint of the collection NFT.
+    pub mint: Account<'info, Mint>,
+    #[account(
+        constraint = nft_mint.supply == 1 && nft_mint.decimals == 0 @ StakingError::InvalidNft,
+    )]
+    pub nft_mint: Account<'info, Mint>,
+    /// CHECK: Verified to be the metadata of nft_mint in the instruction.
+    pub nft_metadata: UncheckedAccount<'info>,
+
+    #[account(
+        init,
+        payer = owner,
+        seeds = [
+            STAKE_TOKEN_ACCOUNT_SEED,
+            stake.key().as_ref(),
+        ],
+        bump,
+        token::mint = nft_mint,
+        token::authority = escrow,
+    )]
+    pub stake_token: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        has_one = owner,
+        constraint = owner_nft_token.mint == nft_mint.key(),
+    )]
+    pub owner_nft_token: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct AddStake<'info> {
+    #[account(
+        mut,
+        has_one = owner,
+        has_one = mint,
+        seeds = [
+            STAKE_ACCOUNT_SEED,
+            owner.key.as_ref(),
+            mint.key().as_ref(),
+            &stake.index.to_le_bytes(),
+        ],
+        bump = stake.bump,
+    )]
+    pub stake: Account<'info, Stake>,
+    #[account(
+        mut,
+        constraint = !escrow.paused && !escrow.emergency @ StakingError::Paused,
+        has_one = escrow_token,
+        has_one = token_program,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    pub owner: Signer<'info>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub escrow_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of mint owned by owner.
+    #[account(
+        mut,
+        constraint = is_token_account(&owner_token, token_program.key, mint.key, Some(owner.key))
+            @ StakingError::InvalidTokenAccount,
+    )]
+    pub owner_token: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct PartialUnstake<'info> {
+    #[account(
+        mut,
+        has_one = owner,
+        has_one = mint,
+        seeds = [
+            STAKE_ACCOUNT_SEED,
+            owner.key.as_ref(),
+            mint.key().as_ref(),
+            &stake.index.to_le_bytes(),
+        ],
+        bump = stake.bump,
+    )]
+    pub stake: Account<'info, Stake>,
+    #[account(
+        mut,
+        constraint = !escrow.emergency @ StakingError::Emergency,
+        has_one = escrow_token,
+        has_one = token_program,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    pub owner: Signer<'info>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub escrow_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of mint owned by owner.
+    #[account(
+        mut,
+        constraint = is_token_account(&owner_token, token_program.key, mint.key, Some(owner.key))
+            @ StakingError::InvalidTokenAccount,
+    )]
+    pub owner_token: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RequestUnstake<'info> {
+    #[account(
+        mut,
+        has_one = owner,
+        has_one = mint,
+        seeds = [
+            STAKE_ACCOUNT_SEED,
+            owner.key.as_ref(),
+            mint.key().as_ref(),
+            &stake.index.to_le_bytes(),
+        ],
+        bump = stake.bump,
+    )]
+    pub stake: Account<'info, Stake>,
+    #[account(
+        mut,
+        constraint = !escrow.emergency @ StakingError::Emergency,
+        has_one = escrow_token,
+        has_one = reward_token,
+        has_one = token_program,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    pub owner: Signer<'info>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub escrow_token: UncheckedAccount<'info>,
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub reward_token: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CompleteUnstake<'info> {
+    #[account(
+        mut,
+        has_one = owner,
+        has_one = mint,
+        seeds = [
+            STAKE_ACCOUNT_SEED,
+            owner.key.as_ref(),
+            mint.key().as_ref(),
+            &stake.index.to_le_bytes(),
+        ],
+        bump = stake.bump,
+    )]
+    pub stake: Account<'info, Stake>,
+    #[account(
+        mut,
+        constraint = !escrow.emergency @ StakingError::Emergency,
+        has_one = escrow_token,
+        has_one = token_program,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    pub owner: Signer<'info>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub escrow_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of mint owned by owner.
+    #[account(
+        mut,
+        constraint = is_token_account(&owner_token, token_program.key, mint.key, Some(owner.key))
+            @ StakingError::InvalidTokenAccount,
+    )]
+    pub owner_token: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct EmergencyWithdraw<'info> {
+    #[account(
+        mut,
+        close = owner,
+        has_one = owner,
+        has_one = mint,
+        seeds = [
+            STAKE_ACCOUNT_SEED,
+            owner.key.as_ref(),
+            mint.key().as_ref(),
+            &stake.index.to_le_bytes(),
+        ],
+        bump = stake.bump,
+    )]
+    pub stake: Account<'info, Stake>,
+    #[account(
+        mut,
+        constraint = escrow.emergency @ StakingError::NotEmergency,
+        has_one = escrow_token,
+        has_one = token_program,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    pub owner: Signer<'info>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub escrow_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of mint owned by owner.
+    #[account(
+        mut,
+        constraint = is_token_account(&owner_token, token_program.key, mint.key, Some(owner.key))
+            @ StakingError::InvalidTokenAccount,
+    )]
+    pub owner_token: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimRewards<'info> {
+    #[account(
+        mut,
+        has_one = owner,
+        has_one = mint,
+        seeds = [
+            STAKE_ACCOUNT_SEED,
+            owner.key.as_ref(),
+            mint.key().as_ref(),
+            &stake.index.to_le_bytes(),
+        ],
+        bump = stake.bump,
+    )]
+    pub stake: Account<'info, Stake>,
+    #[account(
+        mut,
+        constraint = !escrow.emergency @ StakingError::Emergency,
+        has_one = reward_mint,
+        has_one = reward_token,
+        has_one = token_program,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    pub owner: Signer<'info>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: Verified by the escrow.
+    pub reward_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub reward_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of reward_mint owned by owner.
+    #[account(
+        mut,
+        constraint = is_token_account(&owner_reward_token, token_program.key, reward_mint.key, Some(owner.key))
+            @ StakingError::InvalidTokenAccount,
+    )]
+    pub owner_reward_token: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseStakeAccount<'info> {
+    #[account(
+        mut,
+        close = owner,
+        has_one = owner,
+        has_one = mint,
+        seeds = [
+            STAKE_ACCOUNT_SEED,
+            owner.key.as_ref(),
+            mint.key().as_ref(),
+            &stake.index.to_le_bytes(),
+        ],
+        bump = stake.bump,
+    )]
+    pub stake: Account<'info, Stake>,
+    #[account(
+        mut,
+        constraint = !escrow.emergency @ StakingError::Emergency,
+        has_one = escrow_token,
+        has_one = reward_mint,
+        has_one = reward_token,
+        has_one = token_program,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
+    )]
+    pub escrow: Account<'info, Escrow>,
+
+    pub owner: Signer<'info>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: Verified by the escrow.
+    pub reward_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub escrow_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of mint owned by owner.
+    #[account(
+        mut,
+        constraint = is_token_account(&owner_token, token_program.key, mint.key, Some(owner.key))
+            @ StakingError::InvalidTokenAccount,
+    )]
+    pub owner_token: UncheckedAccount<'info>,
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub reward_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of reward_mint owned by owner.
+    #[account(
+        mut,
+        constraint = is_token_account(&owner_reward_token, token_program.key, reward_mint.key, Some(owner.key))
+            @ StakingError::InvalidTokenAccount,
+    )]
+    pub owner_reward_token: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseNftStakeAccount<'info> {
+    #[account(
+        mut,
+        close = owner,
+        has_one = owner,
+        has_one = mint,
+        has_one = nft_mint,
+        has_one = stake_token,
+        seeds = [
+            STAKE_ACCOUNT_SEED,
+            owner.key.as_ref(),
+            mint.key().as_ref(),
+            &stake.index.to_le_bytes(),
+        ],
+        bump = stake.bump,
+    )]
+    pub stake: Account<'info, Stake>,
+    #[account(
+        mut,
+        has_one = reward_mint,
+        has_one = reward_token,
+        constraint = escrow.kind == PoolKind::Nft @ StakingError::InvalidPoolKind,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
     )]
     pub escrow: Account<'info, Escrow>,
 
     #[account(mut)]
-    pub payer: Signer<'info>,
+    pub owner: Signer<'info>,
     pub mint: Account<'info, Mint>,
+    pub nft_mint: Account<'info, Mint>,
+    pub reward_mint: Account<'info, Mint>,
 
+    #[account(mut)]
+    pub stake_token: Account<'info, TokenAccount>,
     #[account(
         mut,
-        has_one = mint,
-        constraint = escrow_token.owner == escrow.key(),
+        has_one = owner,
+        constraint = owner_nft_token.mint == nft_mint.key(),
+    )]
+    pub owner_nft_token: Account<'info, TokenAccount>,
+    #[account(mut)]
+    pub reward_token: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        has_one = owner,
+        constraint = owner_reward_token.mint == escrow.reward_mint,
     )]
-    pub escrow_token: Account<'info, TokenAccount>,
+    pub owner_reward_token: Account<'info, TokenAccount>,
 
     pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct FundEscrowAccount<'info> {
+pub struct ViewPosition<'info> {
+    #[account(has_one = mint)]
+    pub stake: Account<'info, Stake>,
     #[account(
         seeds = [
             ESCROW_ACCOUNT_SEED,
             mint.key().as_ref(),
         ],
-        bump,
+        bump = escrow.bump,
     )]
     pub escrow: Account<'info, Escrow>,
 
-    #[account(mut)]
-    pub payer: Signer<'info>,
-    pub mint: Account<'info, Mint>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+}
 
+#[derive(Accounts)]
+pub struct CreateVault<'info> {
     #[account(
         mut,
-        has_one = mint,
-        constraint = escrow_token.owner == escrow.key(),
+        has_one = payer,
+        has_one = token_program,
+        constraint = escrow.share_mint == Pubkey::default() @ StakingError::InvalidVault,
+        seeds = [
+            ESCROW_ACCOUNT_SEED,
+            mint.key().as_ref(),
+        ],
+        bump = escrow.bump,
     )]
-    pub escrow_token: Account<'info, TokenAccount>,
+    pub escrow: Account<'info, Escrow>,
     #[account(
-        mut,
-        has_one = mint,
-        constraint = payer_token.owner == payer.key(),
+        init,
+        payer = payer,
+        seeds = [
+            VAULT_STAKE_ACCOUNT_SEED,
+            escrow.key().as_ref(),
+        ],
+        bump,
     )]
-    pub payer_token: Account<'info, TokenAccount>,
+    pub vault_stake: Account<'info, Stake>,
 
-    pub token_program: Program<'info, Token>,
-}
+    #[account(mut)]
+    pub payer: Signer<'info>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
 
-#[derive(Accounts)]
-pub struct CreateStakeAccount<'info> {
+    /// CHECK: Created in the instruction.
     #[account(
-        init,
-        payer = owner,
+        mut,
         seeds = [
-            STAKE_ACCOUNT_SEED,
-            owner.key.as_ref(),
-            mint.key().as_ref(),
+            SHARE_MINT_SEED,
+            escrow.key().as_ref(),
         ],
         bump,
     )]
-    pub stake: Account<'info, Stake>,
+    pub share_mint: UncheckedAccount<'info>,
+
+    /// CHECK: Verified by the escrow.
+    pub token_program: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositVault<'info> {
     #[account(
+        mut,
+        constraint = !escrow.paused && !escrow.emergency @ StakingError::Paused,
+        has_one = escrow_token,
+        has_one = reward_token,
+        has_one = share_mint,
+        has_one = token_program,
         seeds = [
             ESCROW_ACCOUNT_SEED,
             mint.key().as_ref(),
         ],
-        bump,
+        bump = escrow.bump,
     )]
     pub escrow: Account<'info, Escrow>,
+    #[account(
+        mut,
+        seeds = [
+            VAULT_STAKE_ACCOUNT_SEED,
+            escrow.key().as_ref(),
+        ],
+        bump = vault_stake.bump,
+    )]
+    pub vault_stake: Account<'info, Stake>,
 
-    #[account(mut)]
     pub owner: Signer<'info>,
-    pub mint: Account<'info, Mint>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub share_mint: UncheckedAccount<'info>,
 
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub escrow_token: UncheckedAccount<'info>,
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub reward_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of mint owned by owner.
     #[account(
         mut,
-        has_one = mint,
-        constraint = escrow_token.owner == escrow.key(),
+        constraint = is_token_account(&owner_token, token_program.key, mint.key, Some(owner.key))
+            @ StakingError::InvalidTokenAccount,
     )]
-    pub escrow_token: Account<'info, TokenAccount>,
+    pub owner_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of share_mint.
     #[account(
         mut,
-        has_one = owner,
-        has_one = mint,
+        constraint = is_token_account(&owner_share_token, token_program.key, share_mint.key, None)
+            @ StakingError::InvalidTokenAccount,
     )]
-    pub owner_token: Account<'info, TokenAccount>,
+    pub owner_share_token: UncheckedAccount<'info>,
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
+    /// CHECK: Verified by the escrow.
+    pub token_program: UncheckedAccount<'info>,
 }
 
 #[derive(Accounts)]
-pub struct CloseStakeAccount<'info> {
+pub struct WithdrawVault<'info> {
     #[account(
         mut,
-        close = owner,
+        has_one = escrow_token,
+        has_one = reward_token,
+        has_one = share_mint,
+        has_one = token_program,
         seeds = [
-            STAKE_ACCOUNT_SEED,
-            owner.key.as_ref(),
+            ESCROW_ACCOUNT_SEED,
             mint.key().as_ref(),
         ],
-        bump,
+        bump = escrow.bump,
     )]
-    pub stake: Account<'info, Stake>,
+    pub escrow: Account<'info, Escrow>,
     #[account(
+        mut,
         seeds = [
-            ESCROW_ACCOUNT_SEED,
-            mint.key().as_ref(),
+            VAULT_STAKE_ACCOUNT_SEED,
+            escrow.key().as_ref(),
         ],
-        bump,
+        bump = vault_stake.bump,
     )]
-    pub escrow: Account<'info, Escrow>,
+    pub vault_stake: Account<'info, Stake>,
 
     pub owner: Signer<'info>,
-    pub mint: Account<'info, Mint>,
+    /// CHECK: Verified by the seeds of the escrow.
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub share_mint: UncheckedAccount<'info>,
 
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub escrow_token: UncheckedAccount<'info>,
+    /// CHECK: Verified by the escrow.
+    #[account(mut)]
+    pub reward_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of mint owned by owner.
     #[account(
         mut,
-        has_one = mint,
-        constraint = escrow_token.owner == escrow.key(),
+        constraint = is_token_account(&owner_token, token_program.key, mint.key, Some(owner.key))
+            @ StakingError::InvalidTokenAccount,
     )]
-    pub escrow_token: Account<'info, TokenAccount>,
+    pub owner_token: UncheckedAccount<'info>,
+    /// CHECK: Verified to be a token account of share_mint owned by owner.
     #[account(
         mut,
-        has_one = owner,
-        has_one = mint,
+        constraint = is_token_account(&owner_share_token, token_program.key, share_mint.key, Some(owner.key))
+            @ StakingError::InvalidTokenAccount,
     )]
-    pub owner_token: Account<'info, TokenAccount>,
+    pub owner_share_token: UncheckedAccount<'info>,
 
-    pub token_program: Program<'info, Token>,
+    /// CHECK: Verified by the escrow.
+    pub token_program: UncheckedAccount<'info>,
 }
 
 impl<'info> FundEscrowAccount<'info> {
-    pub fn transfer_to_escrow(&self, amount: u64) -> Result<()> {
-        let cpi_program = self.token_program.to_account_info();
-        let cpi_accounts = Transfer {
-            from: self.payer_token.to_account_info(),
-            to: self.escrow_token.to_account_info(),
-            authority: self.payer.to_account_info(),
-        };
-        transfer(CpiContext::new(cpi_program, cpi_accounts), amount)
+    pub fn transfer_to_reward_token(&self, amount: u64) -> Result<u64> {
+        transfer_tokens(
+            &self.token_program,
+            &self.payer_token,
+            &self.reward_mint,
+            &self.reward_token,
+            &self.payer,
+            amount,
+            &[],
+        )
     }
 }
 
 impl<'info> CreateStakeAccount<'info> {
-    pub fn transfer_to_escrow(&self, amount: u64) -> Result<()> {
+    pub fn transfer_to_escrow(&self, amount: u64) -> Result<u64> {
+        transfer_tokens(
+            &self.token_program,
+            &self.owner_token,
+            &self.mint,
+            &self.escrow_token,
+            &self.owner,
+            amount,
+            &[],
+        )
+    }
+}
+
+impl<'info> AddStake<'info> {
+    pub fn transfer_to_escrow(&self, amount: u64) -> Result<u64> {
+        transfer_tokens(
+            &self.token_program,
+            &self.owner_token,
+            &self.mint,
+            &self.escrow_token,
+            &self.owner,
+            amount,
+            &[],
+        )
+    }
+}
+
+impl<'info> CreateNftStakeAccount<'info> {
+    pub fn transfer_to_stake_token(&self) -> Result<()> {
         let cpi_program = self.token_program.to_account_info();
         let cpi_accounts = Transfer {
-            from: self.owner_token.to_account_info(),
-            to: self.escrow_token.to_account_info(),
+            from: self.owner_nft_token.to_account_info(),
+            to: self.stake_token.to_account_info(),
             authority: self.owner.to_account_info(),
         };
-        transfer(CpiContext::new(cpi_program, cpi_accounts), amount)
+        transfer(CpiContext::new(cpi_program, cpi_accounts), 1)
     }
 }
 
-impl<'info> CloseStakeAccount<'info> {
-    pub fn transfer_from_escrow(&self, bump: u8, amount: u64) -> Result<()> {
-        let cpi_program = self.token_program.to_account_info();
-        let cpi_accounts = Transfer {
-            from: self.escrow_token.to_account_info(),
-            to: self.owner_token.to_account_info(),
-            authority: self.escrow.to_account_info(),
-        };
-        transfer(
-            CpiContext::new_with_signer(
-                cpi_program,
-                cpi_accounts,
-                &[&[ESCROW_ACCOUNT_SEED, self.mint.key().as_ref(), &[bump]]],
-            ),
+impl<'info> DepositVault<'info> {
+    pub fn compound(&mut self) -> Result<()> {
+        compound_vault(
+            &self.token_program,
+            &mut self.escrow,
+            &mut self.vault_stake,
+            &self.reward_token,
+            &self.mint,
+            &self.escrow_token,
+        )
+    }
+
+    pub fn transfer_to_escrow(&self, amount: u64) -> Result<u64> {
+        transfer_tokens(
+            &self.token_program,
+            &self.owner_token,
+            &self.mint,
+            &self.escrow_token,
+            &self.owner,
             amount,
+            &[],
+        )
+    }
+
+    pub fn mint_shares(&self, shares: u64) -> Result<()> {
+        invoke_token_program(
+            spl_token::instruction::mint_to(
+                &spl_token::id(),
+                self.share_mint.key,
+                self.owner_share_token.key,
+                &self.escrow.key(),
+                &[],
+                shares,
+            )?,
+            &self.token_program,
+            &[
+                self.share_mint.to_account_info(),
+                self.owner_share_token.to_account_info(),
+                self.escrow.to_account_info(),
+            ],
+            &[&[ESCROW_ACCOUNT_SEED, self.escrow.mint.as_ref(), &[self.escrow.bump]]],
+        )
+    }
+}
+
+impl<'info> WithdrawVault<'info> {
+    pub fn compound(&mut self) -> Result<()> {
+        compound_vault(
+            &self.token_program,
+            &mut self.escrow,
+            &mut self.vault_stake,
+            &self.reward_token,
+            &self.mint,
+            &self.escrow_token,
+        )
+    }
+
+    pub fn burn_shares(&self, shares: u64) -> Result<()> {
+        invoke_token_program(
+            spl_token::instruction::burn(
+                &spl_token::id(),
+                self.owner_share_token.key,
+                self.share_mint.key,
+                self.owner.key,
+                &[],
+                shares,
+            )?,
+            &self.token_program,
+            &[
+                self.owner_share_token.to_account_info(),
+                self.share_mint.to_account_info(),
+                self.owner.to_account_info(),
+            ],
+            &[],
         )
     }
 }
 
+/// Restakes the rewards of the vault, which raises the share price.
+fn compound_vault<'info>(
+    token_program: &AccountInfo<'info>,
+    escrow: &mut Account<'info, Escrow>,
+    vault_stake: &mut Account<'info, Stake>,
+    reward_token: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    escrow_token: &AccountInfo<'info>,
+) -> Result<()> {
+    let now = escrow.now()?;
+    escrow.update_reward(now)?;
+    vault_stake.update_reward(escrow)?;
+    let reward = vault_stake.reward;
+    if reward == 0 {
+        return Ok(());
+    }
+
+    // Only the amount received by escrow_token is restaked, net of any transfer fee.
+    vault_stake.reward = 0;
+    let reward = transfer_from_escrow(token_program, escrow, reward_token, mint, escrow_token, reward)?;
+    let new_amount = vault_stake.amount
+        .checked_add(reward)
+        .ok_or(error!(StakingError::TotalAmountOutOfBounds))?;
+    vault_stake.set_amount(escrow, new_amount)
+}
+
+/// Transfers tokens held by the escrow and returns the amount received by `to`.
+fn transfer_from_escrow<'info>(
+    token_program: &AccountInfo<'info>,
+    escrow: &Account<'info, Escrow>,
+    from: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    amount: u64,
+) -> Result<u64> {
+    transfer_tokens(
+        token_program,
+        from,
+        mint,
+        to,
+        &escrow.to_account_info(),
+        amount,
+        &[&[ESCROW_ACCOUNT_SEED, escrow.mint.as_ref(), &[escrow.bump]]],
+    )
+}
+
+fn close_escrow_token_account<'info>(
+    token_program: &Program<'info, Token>,
+    escrow: &Account<'info, Escrow>,
+    account: &Account<'info, TokenAccount>,
+    destination: AccountInfo<'info>,
+) -> Result<()> {
+    let cpi_program = token_program.to_account_info();
+    let cpi_accounts = CloseAccount {
+        account: account.to_account_info(),
+        destination,
+        authority: escrow.to_account_info(),
+    };
+    close_account(CpiContext::new_with_signer(
+        cpi_program,
+        cpi_accounts,
+        &[&[ESCROW_ACCOUNT_SEED, escrow.mint.as_ref(), &[escrow.bump]]],
+    ))
+}
+
+// Anchor only supports the accounts of the token program, so the mints and token accounts of fungible pools, which
+// may belong to the token-2022 program, are read and created below. Token-2022 mints and token accounts start with the
+// layout of the token program, followed by their extensions, and the instructions of the token program are encoded
+// identically by token-2022.
+
+fn is_token_program(program: &Pubkey) -> bool {
+    *program == spl_token::id() || *program == spl_token_2022::id()
+}
+
+fn unpack_mint(mint: &AccountInfo) -> Result<spl_token_2022::state::Mint> {
+    require!(is_token_program(mint.owner), StakingError::InvalidMint);
+    let data = mint.try_borrow_data()?;
+    let state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)
+        .map_err(|_| error!(StakingError::InvalidMint))?;
+    Ok(state.base)
+}
+
+fn unpack_token_account(token: &AccountInfo) -> Result<spl_token_2022::state::Account> {
+    require!(is_token_program(token.owner), StakingError::InvalidTokenAccount);
+    let data = token.try_borrow_data()?;
+    let state = StateWithExtensions::<spl_token_2022::state::Account>::unpack(&data)
+        .map_err(|_| error!(StakingError::InvalidTokenAccount))?;
+    Ok(state.base)
+}
+
+fn token_amount(token: &AccountInfo) -> Result<u64> {
+    Ok(unpack_token_account(token)?.amount)
+}
+
+/// Whether `token` is a token account of `token_program` for `mint`, owned by `owner` if given.
+fn is_token_account(token: &AccountInfo, token_program: &Pubkey, mint: &Pubkey, owner: Option<&Pubkey>) -> bool {
+    if token.owner != token_program {
+        return false;
+    }
+    match unpack_token_account(token) {
+        Ok(state) => state.mint == *mint && owner.map_or(true, |owner| state.owner == *owner),
+        Err(_) => false,
+    }
+}
+
+/// Invokes an instruction built for the token program on `token_program` instead.
+fn invoke_token_program<'info>(
+    mut instruction: Instruction,
+    token_program: &AccountInfo<'info>,
+    accounts: &[AccountInfo<'info>],
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    instruction.program_id = token_program.key();
+    let mut account_infos = accounts.to_vec();
+    account_infos.push(token_program.clone());
+    invoke_signed(&instruction, &account_infos, signer_seeds)?;
+    Ok(())
+}
+
+/// Transfers tokens and returns the amount received by `to`, which is lower than `amount` for mints charging transfer
+/// fees.
+fn transfer_tokens<'info>(
+    token_program: &AccountInfo<'info>,
+    from: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<u64> {
+    let balance = token_amount(to)?;
+    invoke_token_program(
+        spl_token::instruction::transfer_checked(
+            &spl_token::id(),
+            from.key,
+            mint.key,
+            to.key,
+            authority.key,
+            &[],
+            amount,
+            unpack_mint(mint)?.decimals,
+        )?,
+        token_program,
+        &[from.clone(), mint.clone(), to.clone(), authority.clone()],
+        signer_seeds,
+    )?;
+    Ok(token_amount(to)? - balance)
+}
+
+fn create_pda_account<'info>(
+    payer: &AccountInfo<'info>,
+    account: &AccountInfo<'info>,
+    space: usize,
+    owner: &Pubkey,
+    seeds: &[&[u8]],
+    system_program: &AccountInfo<'info>,
+) -> Result<()> {
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            account.key,
+            Rent::get()?.minimum_balance(space),
+            space as u64,
+            owner,
+        ),
+        &[payer.clone(), account.clone(), system_program.clone()],
+        &[seeds],
+    )?;
+    Ok(())
+}
+
+/// Creates a token account of `mint` at the address of `seeds`, with room for the extensions the mint requires.
+fn create_token_account<'info>(
+    token_program: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    token: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    authority: &Pubkey,
+    seeds: &[&[u8]],
+    system_program: &AccountInfo<'info>,
+) -> Result<()> {
+    let space = {
+        let data = mint.try_borrow_data()?;
+        let state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)?;
+        let extensions = ExtensionType::get_required_init_account_extensions(&state.get_extension_types()?);
+        ExtensionType::get_account_len::<spl_token_2022::state::Account>(&extensions)
+    };
+    create_pda_account(payer, token, space, token_program.key, seeds, system_program)?;
+    invoke_token_program(
+        spl_token::instruction::initialize_account3(&spl_token::id(), token.key, mint.key, authority)?,
+        token_program,
+        &[token.clone(), mint.clone()],
+        &[],
+    )
+}
+
+/// Creates a mint without extensions at the address of `seeds`.
+fn create_mint<'info>(
+    token_program: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    decimals: u8,
+    authority: &Pubkey,
+    seeds: &[&[u8]],
+    system_program: &AccountInfo<'info>,
+) -> Result<()> {
+    create_pda_account(payer, mint, spl_token::state::Mint::LEN, token_program.key, seeds, system_program)?;
+    invoke_token_program(
+        spl_token::instruction::initialize_mint2(&spl_token::id(), mint.key, authority, None, decimals)?,
+        token_program,
+        &[mint.clone()],
+        &[],
+    )
+}
+
+/// Checks that `metadata` is the token metadata of `nft_mint` and that the NFT belongs to the verified collection of
+/// `collection_mint`.
+fn verify_collection(metadata: &AccountInfo, nft_mint: &Pubkey, collection_mint: &Pubkey) -> Result<()> {
+    let (metadata_address, _) = mpl_token_metadata::pda::find_metadata_account(nft_mint);
+    require!(
+        metadata.key() == metadata_address && *metadata.owner == mpl_token_metadata::id(),
+        StakingError::InvalidNft
+    );
+
+    let metadata = Metadata::from_account_info(metadata)?;
+    match metadata.collection {
+        Some(collection) if collection.verified && collection.key == *collection_mint => Ok(()),
+        _ => err!(StakingError::InvalidCollection),
+    }
+}
+
 #[account]
 #[derive(Debug, Default, PartialEq, Eq)]
 pub struct Escrow {
+    pub kind: PoolKind,
+    // Unit of all the times and durations of the pool and its stakes.
+    pub time_mode: TimeMode,
     pub payer: Pubkey,
     pub mint: Pubkey,
     pub bump: u8,
+    // Program of the staked and reward tokens, either the token program or the token-2022 program.
+    pub token_program: Pubkey,
+    // Vault holding the staked tokens. Unset for NFT pools, which rejects the instructions for fungible stakes.
+    pub escrow_token: Pubkey,
+    // Rewards are paid in reward_mint from a separate vault, so that stakes are never used to pay rewards.
+    pub reward_mint: Pubkey,
+    pub reward_token: Pubkey,
+    // Funded rewards are emitted at reward_rate tokens per unit of time until reward_end_at, and shared by the stakes
+    // in proportion to their amounts.
+    pub reward_rate: u128,
+    pub reward_end_at: u64,
+    // Reward emitted per staked token until reward_updated_at.
+    pub reward_per_token: u128,
+    pub reward_updated_at: u64,
+    pub total_staked: u64,
+    // Rewards are shared in proportion to the weights of the stakes, which are their amounts boosted by the
+    // multipliers of their lock tiers.
+    pub total_weight: u64,
+    pub lock_tiers: [LockTier; LOCK_TIER_COUNT],
+    // Share of a stake withheld when it is closed before it unlocks, redistributed to the other stakes. Closing a
+    // locked stake is rejected when 0.
+    pub early_withdrawal_penalty_bps: u16,
+    // Unstaked tokens are only withdrawn unbonding_period units of time after the unstake is requested. Stakes exit
+    // instantly when 0.
+    pub unbonding_period: u64,
+    // Unstaked tokens still held in escrow_token, which no longer accrue rewards.
+    pub total_unbonding: u64,
+    // New stakes are rejected while paused.
+    pub paused: bool,
+    // Once in an emergency, rewards stop and stakes can only withdraw their principal, without rewards.
+    pub emergency: bool,
+    // Account the payer transferred the authority over the escrow to, until it accepts it.
+    pub pending_payer: Pubkey,
+    // Mint of the shares of the auto-compounding vault, if the pool has one.
+    pub share_mint: Pubkey,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolKind {
+    Fungible,
+    // Stakes are NFTs of the collection of the pool mint, each counting as a single token.
+    Nft,
+}
+
+impl Default for PoolKind {
+    fn default() -> Self {
+        PoolKind::Fungible
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeMode {
+    UnixTimestamp,
+    // Slots and epochs always increase, unlike the timestamps which are estimated by the validators and can drift.
+    Slot,
+    Epoch,
+}
+
+impl Default for TimeMode {
+    fn default() -> Self {
+        TimeMode::UnixTimestamp
+    }
+}
+
+impl TimeMode {
+    pub fn current_time(&self) -> Result<u64> {
+        let clock = Clock::get()?;
+        match self {
+            TimeMode::UnixTimestamp => {
+                require!(clock.unix_timestamp >= 0, StakingError::InvalidClock);
+                Ok(clock.unix_timestamp as u64)
+            }
+            TimeMode::Slot => Ok(clock.slot),
+            TimeMode::Epoch => Ok(clock.epoch),
+        }
+    }
+
+    /// Converts a number of seconds to the approximate number of units of time.
+    pub fn units_from_seconds(&self, seconds: u64) -> u64 {
+        match self {
+            TimeMode::UnixTimestamp => seconds,
+            TimeMode::Slot => seconds * 1000 / SLOT_DURATION_MS,
+            TimeMode::Epoch => seconds * 1000 / SLOT_DURATION_MS / SLOTS_PER_EPOCH,
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LockTier {
+    pub duration: u64,
+    pub multiplier_bps: u16,
+}
+
+impl Escrow {
+    pub fn init(
+        &mut self,
+        kind: PoolKind,
+        time_mode: TimeMode,
+        payer: Pubkey,
+        mint: Pubkey,
+        bump: u8,
+        reward_mint: Pubkey,
+        reward_token: Pubkey,
+    ) -> Result<()> {
+        self.kind = kind;
+        self.payer = payer;
+        self.mint = mint;
+        self.bump = bump;
+        self.token_program = Pubkey::default();
+        self.escrow_token = Pubkey::default();
+        self.reward_mint = reward_mint;
+        self.reward_token = reward_token;
+        self.reward_rate = 0;
+        self.reward_end_at = 0;
+        self.reward_per_token = 0;
+        self.time_mode = time_mode;
+        self.reward_updated_at = time_mode.current_time()?;
+        self.total_staked = 0;
+        self.total_weight = 0;
+        self.lock_tiers = [
+            LockTier { duration: 0, multiplier_bps: 10_000 },
+            LockTier { duration: time_mode.units_from_seconds(30 * DAY), multiplier_bps: 12_500 },
+            LockTier { duration: time_mode.units_from_seconds(90 * DAY), multiplier_bps: 15_000 },
+            LockTier { duration: time_mode.units_from_seconds(365 * DAY), multiplier_bps: 20_000 },
+        ];
+        self.early_withdrawal_penalty_bps = 0;
+        self.unbonding_period = 0;
+        self.total_unbonding = 0;
+        self.paused = false;
+        self.emergency = false;
+        self.pending_payer = Pubkey::default();
+        self.share_mint = Pubkey::default();
+        Ok(())
+    }
+
+    /// Returns the current time of the pool in the units of its time mode. The time of the pool never goes back, so a
+    /// clock regression only pauses the rewards until the clock catches up.
+    pub fn now(&self) -> Result<u64> {
+        Ok(self.time_mode.current_time()?.max(self.reward_updated_at))
+    }
+
+    /// Adds funded rewards, which are emitted along with any rewards not emitted yet over the next `duration`
+    /// units of time.
+    pub fn add_reward(&mut self, amount: u64, duration: u64, now: u64) -> Result<()> {
+        self.update_reward(now)?;
+
+        let remaining_reward = if now < self.reward_end_at {
+            self.reward_rate
+                .checked_mul((self.reward_end_at - now) as u128)
+                .ok_or(error!(StakingError::RewardOutOfBounds))?
+        } else {
+            0
+        };
+        let total_reward = (amount as u128 * REWARD_PRECISION)
+            .checked_add(remaining_reward)
+            .ok_or(error!(StakingError::RewardOutOfBounds))?;
+
+        self.reward_rate = total_reward / duration as u128;
+        self.reward_end_at = now
+            .checked_add(duration)
+            .ok_or(error!(StakingError::InvalidRewardDuration))?;
+        Ok(())
+    }
+
+    /// Emits the funded rewards that have not been emitted yet at `reward_rate` tokens per unit of time, until they
+    /// run out. The rate is rounded down so that the emission never exceeds what was funded.
+    pub fn set_reward_rate(&mut self, reward_rate: u64, now: u64) -> Result<()> {
+        require!(reward_rate > 0, StakingError::InvalidRewardRate);
+        self.update_reward(now)?;
+
+        let remaining_reward = if now < self.reward_end_at {
+            self.reward_rate * (self.reward_end_at - now) as u128
+        } else {
+            0
+        };
+        require!(remaining_reward > 0, StakingError::InsufficientFunds);
+
+        let reward_rate = reward_rate as u128 * REWARD_PRECISION;
+        let duration = (remaining_reward + reward_rate - 1) / reward_rate;
+        require!(duration <= u64::MAX as u128, StakingError::InvalidRewardDuration);
+        self.add_reward(0, duration as u64, now)
+    }
+
+    /// Removes funded rewards that have not been emitted yet, lowering the reward rate until the end of the emission.
+    pub fn remove_reward(&mut self, amount: u64, now: u64) -> Result<()> {
+        self.update_reward(now)?;
+
+        let remaining_reward = if now < self.reward_end_at {
+            self.reward_rate * (self.reward_end_at - now) as u128
+        } else {
+            0
+        };
+        let removed_reward = amount as u128 * REWARD_PRECISION;
+        require!(
+            removed_reward <= remaining_reward,
+            StakingError::InsufficientFunds
+        );
+
+        if removed_reward == remaining_reward {
+            self.reward_rate = 0;
+            self.reward_end_at = now;
+        } else {
+            self.reward_rate = (remaining_reward - removed_reward) / (self.reward_end_at - now) as u128;
+        }
+        Ok(())
+    }
+
+    /// Accrues the rewards emitted until now to the reward per token. Nothing is emitted while nothing is staked, in
+    /// which case the end of the emission is pushed back instead so that no reward is lost.
+    pub fn update_reward(&mut self, now: u64) -> Result<()> {
+        if now <= self.reward_updated_at {
+            return Ok(());
+        }
+
+        if self.total_weight == 0 {
+            if self.reward_end_at > self.reward_updated_at {
+                self.reward_end_at = self.reward_end_at
+                    .checked_add(now - self.reward_updated_at)
+                    .ok_or(error!(StakingError::RewardOutOfBounds))?;
+            }
+        } else {
+            self.reward_per_token = self.calculate_reward_per_token(now)?;
+        }
+        self.reward_updated_at = now;
+        Ok(())
+    }
+
+    pub fn calculate_reward_per_token(&self, now: u64) -> Result<u128> {
+        let emitted_until = now.min(self.reward_end_at);
+        if self.total_weight == 0 || emitted_until <= self.reward_updated_at {
+            return Ok(self.reward_per_token);
+        }
+
+        let reward = self.reward_rate
+            .checked_mul((emitted_until - self.reward_updated_at) as u128)
+            .ok_or(error!(StakingError::RewardOutOfBounds))?;
+        self.reward_per_token
+            .checked_add(reward / self.total_weight as u128)
+            .ok_or(error!(StakingError::RewardOutOfBounds))
+    }
+
+    /// Shares a penalty, already moved to the reward vault, among the current stakes. With nothing staked, it is
+    /// emitted along with the funded rewards instead.
+    pub fn distribute_penalty(&mut self, amount: u64, now: u64) -> Result<()> {
+        if amount == 0 {
+            return Ok(());
+        }
+
+        if self.total_weight == 0 {
+            let duration = self.reward_end_at.saturating_sub(now).max(1);
+            return self.add_reward(amount, duration, now);
+        }
+
+        self.reward_per_token = self.reward_per_token
+            .checked_add(amount as u128 * REWARD_PRECISION / self.total_weight as u128)
+            .ok_or(error!(StakingError::RewardOutOfBounds))?;
+        Ok(())
+    }
 }
 
 #[account]
@@ -256,28 +2327,84 @@ pub struct Escrow {
 pub struct Stake {
     pub owner: Pubkey,
     pub mint: Pubkey,
+    // Index of the position among the stakes of the owner for the mint.
+    pub index: u64,
     pub amount: u64,
     pub created_at: u64,
     pub bump: u8,
+    // Reward per token of the escrow up to which the reward has been accounted for.
+    pub reward_per_token_paid: u128,
+    // Reward accounted for but not paid yet.
+    pub reward: u64,
+    // The stake can only be withdrawn without penalty from unlock_at.
+    pub unlock_at: u64,
+    pub multiplier_bps: u16,
+    // Amount boosted by the multiplier, which determines the share of the rewards.
+    pub weight: u64,
+    // Unstaked amount that can be withdrawn from unbonding_end_at.
+    pub unbonding_amount: u64,
+    pub unbonding_end_at: u64,
+    // Staked NFT and the token account holding it, for stakes of NFT pools.
+    pub nft_mint: Pubkey,
+    pub stake_token: Pubkey,
 }
 
 impl Stake {
-    pub fn calculate_total_amount(&self) -> Result<u64> {
-        self.amount
-            .checked_add(self.calculate_reward()?)
-            .ok_or(error!(StakingError::TotalAmountOutOfBounds))
+    /// Sets the staked amount and its weight, and updates the totals of the escrow accordingly. The reward must be up
+    /// to date.
+    pub fn set_amount(&mut self, escrow: &mut Escrow, amount: u64) -> Result<()> {
+        // NFTs are staked one at a time, so their multipliers are not scaled down to keep the boost.
+        let weight = match escrow.kind {
+            PoolKind::Fungible => amount as u128 * self.multiplier_bps as u128 / BPS_DENOMINATOR as u128,
+            PoolKind::Nft => amount as u128 * self.multiplier_bps as u128,
+        };
+        if weight > u64::MAX as u128 {
+            return err!(StakingError::TotalAmountOutOfBounds);
+        }
+        let weight = weight as u64;
+
+        escrow.total_staked = (escrow.total_staked - self.amount)
+            .checked_add(amount)
+            .ok_or(error!(StakingError::TotalAmountOutOfBounds))?;
+        escrow.total_weight = (escrow.total_weight - self.weight)
+            .checked_add(weight)
+            .ok_or(error!(StakingError::TotalAmountOutOfBounds))?;
+        self.amount = amount;
+        self.weight = weight;
+        Ok(())
     }
 
-    pub fn calculate_reward(&self) -> Result<u64> {
-        let now = get_current_time()?;
-        if now <= self.created_at {
+    /// Returns the penalty withheld when withdrawing `amount` at `now`, which is rejected if the stake is still locked
+    /// and the escrow has no early withdrawal penalty.
+    pub fn calculate_early_withdrawal_penalty(&self, escrow: &Escrow, amount: u64, now: u64) -> Result<u64> {
+        if amount == 0 || now >= self.unlock_at {
             return Ok(0);
         }
 
-        let time_elapsed = (now - self.created_at) as u128;
-        let reward = (time_elapsed / 5)
-            * (self.amount as u128 * REWARD_RATIO_PER_5_SECONDS_SIGNIFICAND)
-            / (REWARD_RATIO_PER_5_SECONDS_DIVISOR);
+        require!(escrow.early_withdrawal_penalty_bps > 0, StakingError::Locked);
+        Ok((amount as u128 * escrow.early_withdrawal_penalty_bps as u128 / BPS_DENOMINATOR as u128) as u64)
+    }
+
+    /// Accounts for the reward accrued until the reward per token of the escrow, which must be up to date.
+    pub fn update_reward(&mut self, escrow: &Escrow) -> Result<()> {
+        self.reward = self.calculate_reward(escrow, escrow.reward_updated_at)?;
+        self.reward_per_token_paid = escrow.reward_per_token;
+        Ok(())
+    }
+
+    pub fn calculate_total_amount(&self, escrow: &Escrow, now: u64) -> Result<u64> {
+        self.amount
+            .checked_add(self.calculate_reward(escrow, now)?)
+            .ok_or(error!(StakingError::TotalAmountOutOfBounds))
+    }
+
+    pub fn calculate_reward(&self, escrow: &Escrow, now: u64) -> Result<u64> {
+        let reward_per_token = escrow.calculate_reward_per_token(now)?;
+        let reward = (reward_per_token - self.reward_per_token_paid)
+            .checked_mul(self.weight as u128)
+            .ok_or(error!(StakingError::RewardOutOfBounds))?
+            / REWARD_PRECISION
+            + self.reward as u128;
         if reward > u64::MAX as u128 {
             err!(StakingError::RewardOutOfBounds)
         } else {
@@ -286,12 +2413,51 @@ impl Stake {
     }
 }
 
-fn get_current_time() -> Result<u64> {
-    Ok(Clock::get()?.unix_timestamp as u64)
+/// State of a stake at the time view_position is simulated, set as its return data.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PositionView {
+    pub amount: u64,
+    pub reward: u64,
+    pub unlock_at: u64,
+    pub unbonding_amount: u64,
+    pub unbonding_end_at: u64,
+}
+
+#[account]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Positions {
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+    // Number of stake positions ever created by the owner for the mint. Positions are indexed from 0 to count - 1, and
+    // closed positions leave gaps as indices are never reused.
+    pub count: u64,
+    pub bump: u8,
+}
+
+impl Positions {
+    pub fn stake_address(&self, index: u64) -> Pubkey {
+        stake_address(&self.owner, &self.mint, index).0
+    }
+
+    /// Returns the addresses of all the positions of the owner for the mint, including the closed ones.
+    pub fn stake_addresses(&self) -> Vec<Pubkey> {
+        (0..self.count).map(|index| self.stake_address(index)).collect()
+    }
+}
+
+pub fn positions_address(owner: &Pubkey, mint: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[POSITIONS_ACCOUNT_SEED, owner.as_ref(), mint.as_ref()], &ID)
+}
+
+pub fn stake_address(owner: &Pubkey, mint: &Pubkey, index: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[STAKE_ACCOUNT_SEED, owner.as_ref(), mint.as_ref(), &index.to_le_bytes()],
+        &ID,
+    )
 }
 
 #[error_code]
-enum StakingError {
+pub enum StakingError {
     #[msg("The amount cannot be zero.")]
     ZeroAmount,
     #[msg("The reward amount is out of bounds.")]
@@ -300,4 +2466,48 @@ enum StakingError {
     TotalAmountOutOfBounds,
     #[msg("The paying account has insufficient funds.")]
     InsufficientFunds,
+    #[msg("The reward duration is invalid.")]
+    InvalidRewardDuration,
+    #[msg("The stake has insufficient funds.")]
+    InsufficientStake,
+    #[msg("The number of positions is out of bounds.")]
+    PositionsOutOfBounds,
+    #[msg("The lock tier is invalid.")]
+    InvalidLockTier,
+    #[msg("The early withdrawal penalty is invalid.")]
+    InvalidPenalty,
+    #[msg("The stake is locked.")]
+    Locked,
+    #[msg("The stake must be unbonded before it is withdrawn.")]
+    UnbondingRequired,
+    #[msg("The stake is unbonding.")]
+    Unbonding,
+    #[msg("The instruction is not supported by the pool.")]
+    InvalidPoolKind,
+    #[msg("The token is not a valid NFT.")]
+    InvalidNft,
+    #[msg("The NFT does not belong to the verified collection of the pool.")]
+    InvalidCollection,
+    #[msg("The pool is paused.")]
+    Paused,
+    #[msg("The pool is in an emergency.")]
+    Emergency,
+    #[msg("The pool is not in an emergency.")]
+    NotEmergency,
+    #[msg("The authority is invalid.")]
+    InvalidAuthority,
+    #[msg("The vault is invalid.")]
+    InvalidVault,
+    #[msg("The clock is invalid.")]
+    InvalidClock,
+    #[msg("The token program is invalid.")]
+    InvalidTokenProgram,
+    #[msg("The mint is invalid.")]
+    InvalidMint,
+    #[msg("The token account is invalid.")]
+    InvalidTokenAccount,
+    #[msg("The escrow has insufficient funds to cover the stake.")]
+    InsufficientEscrow,
+    #[msg("The reward rate cannot be zero.")]
+    InvalidRewardRate,
 }
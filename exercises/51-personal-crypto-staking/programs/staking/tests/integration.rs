@@ -0,0 +1,1058 @@
+use {
+    anchor_lang::{
+        error::ERROR_CODE_OFFSET, AccountDeserialize, AccountSerialize, AnchorSerialize, InstructionData,
+        ToAccountMetas,
+    },
+    assert_matches::assert_matches,
+    mpl_token_metadata::state::{Collection, Data, Key, Metadata, MAX_METADATA_LEN},
+    solana_program_test::{processor, tokio, BanksClientError, ProgramTest, ProgramTestContext},
+    solana_sdk::{
+        account::{Account, AccountSharedData},
+        clock::Clock,
+        instruction::{Instruction, InstructionError},
+        program_error::ProgramError,
+        pubkey::Pubkey,
+        rent::Rent,
+        signature::{Keypair, Signer},
+        system_instruction, system_program,
+        transaction::{Transaction, TransactionError},
+    },
+    spl_token_2022::{
+        extension::{transfer_fee, ExtensionType, StateWithExtensions, StateWithExtensionsMut},
+        state::{Account as TokenAccount, Mint},
+    },
+    staking::{
+        self, accounts, instruction, positions_address, stake_address, Escrow, PoolKind, Positions, Stake,
+        StakingError, TimeMode, ESCROW_ACCOUNT_SEED, ESCROW_TOKEN_ACCOUNT_SEED, REWARD_TOKEN_ACCOUNT_SEED,
+        SHARE_MINT_SEED, STAKE_TOKEN_ACCOUNT_SEED, VAULT_STAKE_ACCOUNT_SEED,
+    },
+};
+
+const YEAR: u64 = 365 * 24 * 60 * 60;
+const REWARD_PRECISION: u128 = 1_000_000_000_000;
+
+#[test]
+fn test_reward_without_elapsed_time() {
+    let escrow = Escrow {
+        reward_rate: 1000 * REWARD_PRECISION,
+        reward_end_at: 1000,
+        reward_per_token: 5 * REWARD_PRECISION,
+        reward_updated_at: 100,
+        total_staked: 10,
+        total_weight: 10,
+        ..Escrow::default()
+    };
+    let stake = Stake {
+        amount: 10,
+        reward_per_token_paid: 2 * REWARD_PRECISION,
+        reward: 7,
+        weight: 10,
+        ..Stake::default()
+    };
+
+    // Nothing is emitted without elapsed time, nor while the clock is behind, so only the reward per token accrued so
+    // far is paid on top of the accounted reward.
+    assert_eq!(stake.calculate_reward(&escrow, 100).unwrap(), 37);
+    assert_eq!(stake.calculate_reward(&escrow, 50).unwrap(), 37);
+    assert_eq!(stake.calculate_total_amount(&escrow, 100).unwrap(), 47);
+    assert_eq!(stake.calculate_reward(&escrow, 101).unwrap(), 1037);
+
+    // Nor past the end of the rewards.
+    let escrow = Escrow {
+        reward_updated_at: 1000,
+        ..escrow
+    };
+    assert_eq!(stake.calculate_reward(&escrow, 2000).unwrap(), 37);
+}
+
+#[test]
+fn test_reward_out_of_bounds() {
+    let escrow = Escrow {
+        reward_per_token: u64::MAX as u128 * REWARD_PRECISION,
+        reward_updated_at: 100,
+        total_staked: 1,
+        total_weight: 1,
+        ..Escrow::default()
+    };
+    let stake = Stake {
+        amount: 1,
+        weight: 1,
+        ..Stake::default()
+    };
+
+    // The largest reward is paid in full, but not the principal along with it.
+    assert_eq!(stake.calculate_reward(&escrow, 100).unwrap(), u64::MAX);
+    assert_error(stake.calculate_total_amount(&escrow, 100), StakingError::TotalAmountOutOfBounds);
+
+    // Rewards beyond u64 are rejected.
+    let stake = Stake {
+        weight: 2,
+        ..stake
+    };
+    assert_error(stake.calculate_reward(&escrow, 100), StakingError::RewardOutOfBounds);
+    assert_error(stake.calculate_total_amount(&escrow, 100), StakingError::RewardOutOfBounds);
+
+    // As are rewards overflowing while being computed.
+    let escrow = Escrow {
+        reward_per_token: u128::MAX,
+        ..escrow
+    };
+    assert_error(stake.calculate_reward(&escrow, 100), StakingError::RewardOutOfBounds);
+
+    // And emitted rewards or reward per token overflowing while being accrued.
+    let escrow = Escrow {
+        reward_rate: u128::MAX / 2,
+        reward_end_at: 1000,
+        reward_per_token: u128::MAX / 2 + 2,
+        ..escrow
+    };
+    assert_error(stake.calculate_reward(&escrow, 101), StakingError::RewardOutOfBounds);
+    assert_error(stake.calculate_reward(&escrow, 103), StakingError::RewardOutOfBounds);
+}
+
+#[test]
+fn test_set_reward_rate() {
+    let mut escrow = Escrow {
+        reward_rate: 10 * REWARD_PRECISION,
+        reward_end_at: 1000,
+        reward_updated_at: 0,
+        total_staked: 10,
+        total_weight: 10,
+        ..Escrow::default()
+    };
+
+    // Rewards emitted so far are accrued with the old rate, and the rest of the budget is emitted at the new rate.
+    escrow.set_reward_rate(5, 100).unwrap();
+    assert_eq!(escrow.reward_per_token, 100 * REWARD_PRECISION);
+    assert_eq!(escrow.reward_updated_at, 100);
+    assert_eq!(escrow.reward_rate, 5 * REWARD_PRECISION);
+    assert_eq!(escrow.reward_end_at, 1900);
+
+    // The rate is rounded down so that the budget is never exceeded.
+    escrow.set_reward_rate(7, 100).unwrap();
+    assert_eq!(escrow.reward_end_at, 100 + 1286);
+    assert!(escrow.reward_rate <= 7 * REWARD_PRECISION);
+    assert!(escrow.reward_rate * 1286 <= 9000 * REWARD_PRECISION);
+
+    assert_error(escrow.set_reward_rate(0, 100), StakingError::InvalidRewardRate);
+    assert_error(escrow.set_reward_rate(5, 1386), StakingError::InsufficientFunds);
+}
+
+#[tokio::test]
+async fn test_update_reward_rate() {
+    let mut tc = TestingContext::new(TimeMode::UnixTimestamp).await;
+    assert_matches!(tc.fund(1000, 100).await, Ok(()));
+    let stake = tc.create_stake(1).await;
+
+    tc.warp(50).await;
+    assert_eq!(tc.claim_rewards(stake).await, 500);
+
+    // Only the payer can change the rate.
+    let not_payer = Keypair::new();
+    assert_matches!(tc.update_reward_rate(Some(&not_payer), 5).await, Err(_));
+
+    // The rest of the budget is emitted at the new rate, so the emission lasts twice as long.
+    assert_matches!(tc.update_reward_rate(None, 5).await, Ok(()));
+    tc.warp(50).await;
+    assert_eq!(tc.claim_rewards(stake).await, 250);
+    tc.warp(100).await;
+    assert_eq!(tc.claim_rewards(stake).await, 250);
+
+    // Nothing is left to emit.
+    let reward_token = tc.reward_token;
+    assert_eq!(tc.token_amount(reward_token).await, 0);
+    assert_staking_error(tc.update_reward_rate(None, 5).await, StakingError::InsufficientFunds);
+}
+
+#[tokio::test]
+async fn test_long_horizon_accrual() {
+    let mut tc = TestingContext::new(TimeMode::UnixTimestamp).await;
+
+    // The largest funding over a century, shared by a single token, must not overflow the reward per token.
+    let amount = u64::MAX / 2;
+    assert_matches!(tc.fund(amount, 100 * YEAR).await, Ok(()));
+    let stake = tc.create_stake(1).await;
+
+    tc.warp(50 * YEAR).await;
+    let reward = tc.claim_rewards(stake).await;
+    assert!(amount / 2 - reward <= 1);
+
+    // Nothing is emitted past the end of the rewards, and at most rounding dust is left in the reward vault.
+    tc.warp(60 * YEAR).await;
+    let reward = reward + tc.claim_rewards(stake).await;
+    assert!(amount - reward <= 2);
+    let reward_token = tc.reward_token;
+    assert_eq!(tc.token_amount(reward_token).await, amount - reward);
+}
+
+#[tokio::test]
+async fn test_timestamp_regression() {
+    let mut tc = TestingContext::new(TimeMode::UnixTimestamp).await;
+
+    // Rewards are emitted at 1000000 tokens per second to the only stake.
+    assert_matches!(tc.fund(1_000_000_000, 1000).await, Ok(()));
+    let stake = tc.create_stake(1_000_000).await;
+
+    tc.warp(100).await;
+    assert_eq!(tc.claim_rewards(stake).await, 100_000_000);
+
+    // Nothing accrues while the clock is behind the time of the pool.
+    tc.rewind(1000).await;
+    assert_eq!(tc.claim_rewards(stake).await, 0);
+    tc.warp(500).await;
+    assert_eq!(tc.claim_rewards(stake).await, 0);
+    assert_eq!(tc.escrow().await.reward_updated_at, tc.now().await + 500);
+
+    // Accrual resumes once the clock catches up.
+    tc.warp(600).await;
+    assert_eq!(tc.claim_rewards(stake).await, 100_000_000);
+
+    // Negative timestamps are rejected.
+    let now = tc.now().await;
+    tc.rewind(now + 1).await;
+    assert_staking_error(tc.try_claim_rewards(stake).await, StakingError::InvalidClock);
+}
+
+#[tokio::test]
+async fn test_slot_mode() {
+    let mut tc = TestingContext::new(TimeMode::Slot).await;
+    assert_eq!(tc.escrow().await.lock_tiers[1].duration, 30 * 24 * 60 * 60 * 5 / 2);
+
+    // Rewards are emitted at 1000000 tokens per slot to the only stake.
+    assert_matches!(tc.fund(1_000_000_000, 1000).await, Ok(()));
+    let stake = tc.create_stake(1_000_000).await;
+    let staked_at = tc.escrow().await.reward_updated_at;
+
+    // Only slots count, however far the timestamp moves.
+    tc.warp(YEAR).await;
+    let reward = tc.claim_rewards(stake).await;
+    let slots = tc.escrow().await.reward_updated_at - staked_at;
+    assert!(slots >= 1);
+    assert_eq!(reward, slots * 1_000_000);
+
+    tc.warp_slots(100).await;
+    let updated_at = tc.escrow().await.reward_updated_at;
+    let reward = tc.claim_rewards(stake).await;
+    let slots = tc.escrow().await.reward_updated_at - updated_at;
+    assert!(slots >= 100);
+    assert_eq!(reward, slots * 1_000_000);
+}
+
+#[tokio::test]
+async fn test_transfer_fee_mint() {
+    // The token-2022 mint charges 1% of every transfer.
+    let mut tc = TestingContext::with_token_program(TimeMode::UnixTimestamp, spl_token_2022::id(), 100).await;
+
+    // Only the amounts received by the pool are emitted and staked.
+    assert_matches!(tc.fund(1_000_000, 1000).await, Ok(()));
+    let reward_token = tc.reward_token;
+    assert_eq!(tc.token_amount(reward_token).await, 990_000);
+
+    let stake = tc.create_stake(1_000_000).await;
+    let escrow_token = tc.escrow_token;
+    assert_eq!(tc.token_amount(escrow_token).await, 990_000);
+    assert_eq!(tc.stake(stake).await.amount, 990_000);
+    assert_eq!(tc.escrow().await.total_staked, 990_000);
+
+    // Rewards are paid net of the fee.
+    tc.warp(1000).await;
+    assert_eq!(tc.claim_rewards(stake).await, 980_100);
+    assert_eq!(tc.token_amount(reward_token).await, 0);
+}
+
+#[tokio::test]
+async fn test_insufficient_escrow() {
+    let mut tc = TestingContext::new(TimeMode::UnixTimestamp).await;
+    assert_matches!(tc.fund(1_000_000, 1000).await, Ok(()));
+    let stake = tc.create_stake(1_000_000).await;
+    tc.warp(100).await;
+
+    // Closing is rejected as a whole when the reward vault cannot pay the reward.
+    let reward_token = tc.reward_token;
+    tc.set_token_amount(reward_token, 0).await;
+    assert_staking_error(tc.try_close_stake(stake).await, StakingError::InsufficientEscrow);
+
+    // Or when the escrow cannot return the principal.
+    tc.warp_slots(1).await;
+    tc.set_token_amount(reward_token, 1_000_000).await;
+    let escrow_token = tc.escrow_token;
+    tc.set_token_amount(escrow_token, 999_999).await;
+    assert_staking_error(tc.try_close_stake(stake).await, StakingError::InsufficientEscrow);
+    assert_eq!(tc.stake(stake).await.amount, 1_000_000);
+    assert_eq!(tc.escrow().await.total_staked, 1_000_000);
+
+    // The stake closes once the vaults can cover it.
+    tc.warp_slots(1).await;
+    tc.set_token_amount(escrow_token, 1_000_000).await;
+    assert_matches!(tc.try_close_stake(stake).await, Ok(()));
+    assert!(tc.ctx.banks_client.get_account(stake).await.unwrap().is_none());
+    assert_eq!(tc.token_amount(escrow_token).await, 0);
+}
+
+#[tokio::test]
+async fn test_vault_unbonding() {
+    let mut tc = TestingContext::new(TimeMode::UnixTimestamp).await;
+    let owner_share_token = tc.create_vault().await;
+    assert_matches!(tc.deposit_vault(owner_share_token, 1000).await, Ok(()));
+    assert_eq!(tc.token_amount(owner_share_token).await, 1000);
+    tc.warp_slots(1).await;
+
+    // Vault shares are withdrawn at once, so the vault is closed while the pool has an unbonding period.
+    tc.update_unbonding_period(100).await;
+    assert_staking_error(tc.withdraw_vault(owner_share_token, 1000).await, StakingError::UnbondingRequired);
+    assert_staking_error(tc.deposit_vault(owner_share_token, 1000).await, StakingError::UnbondingRequired);
+
+    tc.update_unbonding_period(0).await;
+    assert_matches!(tc.withdraw_vault(owner_share_token, 1000).await, Ok(()));
+    assert_eq!(tc.token_amount(owner_share_token).await, 0);
+    assert_eq!(tc.escrow().await.total_staked, 0);
+}
+
+#[tokio::test]
+async fn test_vault_leftover() {
+    let mut tc = TestingContext::new(TimeMode::UnixTimestamp).await;
+    let owner_share_token = tc.create_vault().await;
+    let stake = tc.create_stake(1000).await;
+
+    // Assets left in the vault without any shares are not given to the next depositor, but shared with the stakes.
+    tc.set_vault_leftover(500).await;
+    assert_matches!(tc.deposit_vault(owner_share_token, 1000).await, Ok(()));
+    assert_eq!(tc.token_amount(owner_share_token).await, 1000);
+
+    let (vault_stake, _) = tc.vault_addresses();
+    let vault_stake = tc.stake(vault_stake).await;
+    let escrow = tc.escrow().await;
+    assert_eq!(vault_stake.amount, 1000);
+    assert_eq!(vault_stake.reward_per_token_paid, escrow.reward_per_token);
+    assert_eq!(escrow.total_staked, 2000);
+    assert_eq!(tc.claim_rewards(stake).await, 500);
+}
+
+#[tokio::test]
+async fn test_nft_stake() {
+    let mut tc = TestingContext::nft(TimeMode::UnixTimestamp).await;
+    assert_eq!(tc.escrow().await.kind, PoolKind::Nft);
+
+    // Rewards are emitted at 1000 tokens per second to the only stake.
+    assert_matches!(tc.fund(1_000_000, 1000).await, Ok(()));
+    let collection = tc.mint;
+    let (nft_mint, owner_nft_token) = tc.create_nft(Some(Collection { verified: true, key: collection })).await;
+    let (stake, result) = tc.create_nft_stake(nft_mint, owner_nft_token, 0).await;
+    assert_matches!(result, Ok(()));
+
+    // The NFT is held by the stake and counts as a single token.
+    let stake_token = tc.stake(stake).await.stake_token;
+    assert_eq!(tc.token_amount(owner_nft_token).await, 0);
+    assert_eq!(tc.token_amount(stake_token).await, 1);
+    assert_eq!(tc.stake(stake).await.amount, 1);
+    assert_eq!(tc.escrow().await.total_staked, 1);
+
+    // Locked NFT stakes cannot be closed early, as the NFT cannot be partially withheld.
+    let (locked_nft_mint, locked_owner_nft_token) =
+        tc.create_nft(Some(Collection { verified: true, key: collection })).await;
+    let (locked_stake, result) = tc.create_nft_stake(locked_nft_mint, locked_owner_nft_token, 1).await;
+    assert_matches!(result, Ok(()));
+    assert_staking_error(tc.try_close_nft_stake(locked_stake, locked_owner_nft_token).await, StakingError::Locked);
+
+    // Closing returns the NFT with its rewards, shared with the locked stake in proportion to their weights.
+    tc.warp(1000).await;
+    let owner_token = tc.owner_token;
+    let before = tc.token_amount(owner_token).await;
+    assert_matches!(tc.try_close_nft_stake(stake, owner_nft_token).await, Ok(()));
+    assert!(tc.ctx.banks_client.get_account(stake).await.unwrap().is_none());
+    assert!(tc.ctx.banks_client.get_account(stake_token).await.unwrap().is_none());
+    assert_eq!(tc.token_amount(owner_nft_token).await, 1);
+    let reward = tc.token_amount(owner_token).await - before;
+    assert!(reward > 0 && reward < 1_000_000);
+    assert_eq!(tc.escrow().await.total_staked, 1);
+}
+
+#[tokio::test]
+async fn test_nft_stake_rejections() {
+    let mut tc = TestingContext::nft(TimeMode::UnixTimestamp).await;
+    let collection = tc.mint;
+    let verified = Some(Collection { verified: true, key: collection });
+
+    // Tokens with a supply other than one are not NFTs.
+    let (mint, token) = tc.create_nft_with_supply(2, verified.clone()).await;
+    let (_, result) = tc.create_nft_stake(mint, token, 0).await;
+    assert_staking_error(result, StakingError::InvalidNft);
+
+    // NFTs must belong to the verified collection of the pool.
+    let (mint, token) = tc.create_nft(None).await;
+    let (_, result) = tc.create_nft_stake(mint, token, 0).await;
+    assert_staking_error(result, StakingError::InvalidCollection);
+
+    let (mint, token) = tc.create_nft(Some(Collection { verified: false, key: collection })).await;
+    let (_, result) = tc.create_nft_stake(mint, token, 0).await;
+    assert_staking_error(result, StakingError::InvalidCollection);
+
+    let (mint, token) = tc.create_nft(Some(Collection { verified: true, key: Pubkey::new_unique() })).await;
+    let (_, result) = tc.create_nft_stake(mint, token, 0).await;
+    assert_staking_error(result, StakingError::InvalidCollection);
+
+    // The metadata must be the metadata account of the NFT.
+    let (mint, token) = tc.create_nft(verified.clone()).await;
+    let (other_mint, _) = tc.create_nft(verified.clone()).await;
+    let (other_metadata, _) = mpl_token_metadata::pda::find_metadata_account(&other_mint);
+    let (_, result) = tc.try_create_nft_stake(mint, other_metadata, token, 0).await;
+    assert_staking_error(result, StakingError::InvalidNft);
+
+    // Owned by the token metadata program.
+    tc.set_metadata(mint, verified.clone(), system_program::id());
+    let (_, result) = tc.create_nft_stake(mint, token, 0).await;
+    assert_staking_error(result, StakingError::InvalidNft);
+
+    // None of the rejected NFTs were staked, and a valid one still is.
+    assert_eq!(tc.escrow().await.total_staked, 0);
+    let (mint, token) = tc.create_nft(verified).await;
+    let (_, result) = tc.create_nft_stake(mint, token, 0).await;
+    assert_matches!(result, Ok(()));
+    assert_eq!(tc.escrow().await.total_staked, 1);
+}
+
+struct TestingContext {
+    ctx: ProgramTestContext,
+    token_program: Pubkey,
+    mint: Pubkey,
+    reward_mint: Pubkey,
+    escrow: Pubkey,
+    escrow_token: Pubkey,
+    reward_token: Pubkey,
+    owner_token: Pubkey,
+}
+
+impl TestingContext {
+    async fn new(time_mode: TimeMode) -> Self {
+        Self::with_token_program(time_mode, spl_token::id(), 0).await
+    }
+
+    /// Creates a pool of a mint of `token_program`, which charges `transfer_fee_bps` of every transfer if not 0.
+    async fn with_token_program(time_mode: TimeMode, token_program: Pubkey, transfer_fee_bps: u16) -> Self {
+        let mut ctx = start().await;
+        let (mint, owner_token) = create_mint_and_token(&mut ctx, &token_program, transfer_fee_bps, u64::MAX).await;
+        let (escrow, _) = Pubkey::find_program_address(&[ESCROW_ACCOUNT_SEED, mint.as_ref()], &staking::id());
+        let (escrow_token, _) =
+            Pubkey::find_program_address(&[ESCROW_TOKEN_ACCOUNT_SEED, escrow.as_ref()], &staking::id());
+        let (reward_token, _) =
+            Pubkey::find_program_address(&[REWARD_TOKEN_ACCOUNT_SEED, escrow.as_ref()], &staking::id());
+
+        let mut tc = TestingContext {
+            ctx,
+            token_program,
+            mint,
+            reward_mint: mint,
+            escrow,
+            escrow_token,
+            reward_token,
+            owner_token,
+        };
+
+        let payer = tc.ctx.payer.pubkey();
+        let (positions, _) = positions_address(&payer, &mint);
+        let instructions = [
+            Instruction {
+                program_id: staking::id(),
+                accounts: accounts::CreateEscrowAccount {
+                    escrow,
+                    payer,
+                    mint,
+                    reward_mint: mint,
+                    escrow_token,
+                    reward_token,
+                    token_program,
+                    system_program: system_program::id(),
+                }.to_account_metas(None),
+                data: instruction::CreateEscrowAccount { time_mode }.data(),
+            },
+            Instruction {
+                program_id: staking::id(),
+                accounts: accounts::CreatePositionsAccount {
+                    positions,
+                    owner: payer,
+                    mint,
+                    system_program: system_program::id(),
+                }.to_account_metas(None),
+                data: instruction::CreatePositionsAccount {}.data(),
+            },
+        ];
+        tc.process(&instructions).await.unwrap();
+        tc
+    }
+
+    /// Creates an NFT pool for the collection of a new collection NFT, with rewards paid in another token held by the
+    /// payer in `owner_token`.
+    async fn nft(time_mode: TimeMode) -> Self {
+        let mut ctx = start().await;
+        let token_program = spl_token::id();
+        let (mint, _) = create_mint_and_token(&mut ctx, &token_program, 0, 1).await;
+        let (reward_mint, owner_token) = create_mint_and_token(&mut ctx, &token_program, 0, u64::MAX).await;
+        let (escrow, _) = Pubkey::find_program_address(&[ESCROW_ACCOUNT_SEED, mint.as_ref()], &staking::id());
+        let (reward_token, _) =
+            Pubkey::find_program_address(&[REWARD_TOKEN_ACCOUNT_SEED, escrow.as_ref()], &staking::id());
+
+        let mut tc = TestingContext {
+            ctx,
+            token_program,
+            mint,
+            reward_mint,
+            escrow,
+            escrow_token: Pubkey::default(),
+            reward_token,
+            owner_token,
+        };
+
+        let payer = tc.ctx.payer.pubkey();
+        let (positions, _) = positions_address(&payer, &mint);
+        let instructions = [
+            Instruction {
+                program_id: staking::id(),
+                accounts: accounts::CreateNftEscrowAccount {
+                    escrow,
+                    payer,
+                    mint,
+                    reward_mint,
+                    reward_token,
+                    token_program,
+                    system_program: system_program::id(),
+                    rent: solana_sdk::sysvar::rent::id(),
+                }.to_account_metas(None),
+                data: instruction::CreateNftEscrowAccount { time_mode }.data(),
+            },
+            Instruction {
+                program_id: staking::id(),
+                accounts: accounts::CreatePositionsAccount {
+                    positions,
+                    owner: payer,
+                    mint,
+                    system_program: system_program::id(),
+                }.to_account_metas(None),
+                data: instruction::CreatePositionsAccount {}.data(),
+            },
+        ];
+        tc.process(&instructions).await.unwrap();
+        tc
+    }
+
+    async fn now(&mut self) -> u64 {
+        let clock: Clock = self.ctx.banks_client.get_sysvar().await.unwrap();
+        clock.unix_timestamp as u64
+    }
+
+    /// Moves to the next slot with the clock moved forward by the given number of seconds.
+    async fn warp(&mut self, seconds: u64) {
+        self.set_clock(1, seconds as i64).await;
+    }
+
+    /// Moves to the next slot with the clock moved back by the given number of seconds.
+    async fn rewind(&mut self, seconds: u64) {
+        self.set_clock(1, -(seconds as i64)).await;
+    }
+
+    /// Moves forward by the given number of slots without moving the clock.
+    async fn warp_slots(&mut self, slots: u64) {
+        self.set_clock(slots, 0).await;
+    }
+
+    async fn set_clock(&mut self, slots: u64, seconds: i64) {
+        let mut clock: Clock = self.ctx.banks_client.get_sysvar().await.unwrap();
+        clock.slot += slots;
+        clock.unix_timestamp += seconds;
+        self.ctx.warp_to_slot(clock.slot).unwrap();
+        self.ctx.set_sysvar(&clock);
+        self.ctx.last_blockhash = self.ctx.banks_client.get_latest_blockhash().await.unwrap();
+    }
+
+    async fn process(&mut self, instructions: &[Instruction]) -> Result<(), BanksClientError> {
+        let payer = &self.ctx.payer;
+        let mut transaction = Transaction::new_with_payer(instructions, Some(&payer.pubkey()));
+        transaction.sign(&[payer], self.ctx.last_blockhash);
+        self.ctx.banks_client.process_transaction(transaction).await
+    }
+
+    async fn fund(&mut self, amount: u64, duration: u64) -> Result<(), BanksClientError> {
+        let instruction = Instruction {
+            program_id: staking::id(),
+            accounts: accounts::FundEscrowAccount {
+                escrow: self.escrow,
+                payer: self.ctx.payer.pubkey(),
+                mint: self.mint,
+                reward_mint: self.reward_mint,
+                reward_token: self.reward_token,
+                payer_token: self.owner_token,
+                token_program: self.token_program,
+            }.to_account_metas(None),
+            data: instruction::FundEscrowAccount { amount, duration }.data(),
+        };
+        self.process(&[instruction]).await
+    }
+
+    /// Creates a stake position without lock and returns its address.
+    async fn create_stake(&mut self, amount: u64) -> Pubkey {
+        let owner = self.ctx.payer.pubkey();
+        let (positions, _) = positions_address(&owner, &self.mint);
+        let account = self.ctx.banks_client.get_account(positions).await.unwrap().unwrap();
+        let index = Positions::try_deserialize(&mut account.data.as_slice()).unwrap().count;
+        let (stake, _) = stake_address(&owner, &self.mint, index);
+
+        let instruction = Instruction {
+            program_id: staking::id(),
+            accounts: accounts::CreateStakeAccount {
+                positions,
+                stake,
+                escrow: self.escrow,
+                owner,
+                mint: self.mint,
+                escrow_token: self.escrow_token,
+                owner_token: self.owner_token,
+                token_program: self.token_program,
+                system_program: system_program::id(),
+            }.to_account_metas(None),
+            data: instruction::CreateStakeAccount { amount, lock_tier: 0 }.data(),
+        };
+        self.process(&[instruction]).await.unwrap();
+        stake
+    }
+
+    async fn try_claim_rewards(&mut self, stake: Pubkey) -> Result<(), BanksClientError> {
+        let instruction = Instruction {
+            program_id: staking::id(),
+            accounts: accounts::ClaimRewards {
+                stake,
+                escrow: self.escrow,
+                owner: self.ctx.payer.pubkey(),
+                mint: self.mint,
+                reward_mint: self.reward_mint,
+                reward_token: self.reward_token,
+                owner_reward_token: self.owner_token,
+                token_program: self.token_program,
+            }.to_account_metas(None),
+            data: instruction::ClaimRewards {}.data(),
+        };
+        self.process(&[instruction]).await
+    }
+
+    /// Claims the rewards of the stake and returns the claimed amount.
+    async fn claim_rewards(&mut self, stake: Pubkey) -> u64 {
+        let owner_token = self.owner_token;
+        let before = self.token_amount(owner_token).await;
+        self.try_claim_rewards(stake).await.unwrap();
+        // Transactions identical to a previous one in the same slot would be rejected as duplicates.
+        self.warp_slots(1).await;
+        self.token_amount(owner_token).await - before
+    }
+
+    async fn try_close_stake(&mut self, stake: Pubkey) -> Result<(), BanksClientError> {
+        let instruction = Instruction {
+            program_id: staking::id(),
+            accounts: accounts::CloseStakeAccount {
+                stake,
+                escrow: self.escrow,
+                owner: self.ctx.payer.pubkey(),
+                mint: self.mint,
+                reward_mint: self.reward_mint,
+                escrow_token: self.escrow_token,
+                owner_token: self.owner_token,
+                reward_token: self.reward_token,
+                owner_reward_token: self.owner_token,
+                token_program: self.token_program,
+            }.to_account_metas(None),
+            data: instruction::CloseStakeAccount {}.data(),
+        };
+        self.process(&[instruction]).await
+    }
+
+    /// Creates an NFT held by the payer, with metadata in the given collection.
+    async fn create_nft(&mut self, collection: Option<Collection>) -> (Pubkey, Pubkey) {
+        self.create_nft_with_supply(1, collection).await
+    }
+
+    async fn create_nft_with_supply(&mut self, supply: u64, collection: Option<Collection>) -> (Pubkey, Pubkey) {
+        let (nft_mint, owner_nft_token) = create_mint_and_token(&mut self.ctx, &spl_token::id(), 0, supply).await;
+        self.set_metadata(nft_mint, collection, mpl_token_metadata::id());
+        (nft_mint, owner_nft_token)
+    }
+
+    /// Writes the token metadata account of the NFT, as created by the token metadata program when owned by it.
+    fn set_metadata(&mut self, nft_mint: Pubkey, collection: Option<Collection>, owner: Pubkey) {
+        let metadata = Metadata {
+            key: Key::MetadataV1,
+            update_authority: self.ctx.payer.pubkey(),
+            mint: nft_mint,
+            data: Data {
+                name: "NFT".to_string(),
+                symbol: "NFT".to_string(),
+                uri: "https://example.com/nft.json".to_string(),
+                seller_fee_basis_points: 0,
+                creators: None,
+            },
+            primary_sale_happened: false,
+            is_mutable: true,
+            edition_nonce: None,
+            token_standard: None,
+            collection,
+            uses: None,
+        };
+        let mut data = metadata.try_to_vec().unwrap();
+        data.resize(MAX_METADATA_LEN, 0);
+
+        let (metadata_address, _) = mpl_token_metadata::pda::find_metadata_account(&nft_mint);
+        let account = Account {
+            lamports: Rent::default().minimum_balance(data.len()),
+            data,
+            owner,
+            executable: false,
+            rent_epoch: 0,
+        };
+        self.ctx.set_account(&metadata_address, &AccountSharedData::from(account));
+    }
+
+    /// Stakes the NFT with the lock tier and returns the address of the stake.
+    async fn create_nft_stake(
+        &mut self,
+        nft_mint: Pubkey,
+        owner_nft_token: Pubkey,
+        lock_tier: u8,
+    ) -> (Pubkey, Result<(), BanksClientError>) {
+        let (nft_metadata, _) = mpl_token_metadata::pda::find_metadata_account(&nft_mint);
+        self.try_create_nft_stake(nft_mint, nft_metadata, owner_nft_token, lock_tier).await
+    }
+
+    async fn try_create_nft_stake(
+        &mut self,
+        nft_mint: Pubkey,
+        nft_metadata: Pubkey,
+        owner_nft_token: Pubkey,
+        lock_tier: u8,
+    ) -> (Pubkey, Result<(), BanksClientError>) {
+        let owner = self.ctx.payer.pubkey();
+        let (positions, _) = positions_address(&owner, &self.mint);
+        let account = self.ctx.banks_client.get_account(positions).await.unwrap().unwrap();
+        let index = Positions::try_deserialize(&mut account.data.as_slice()).unwrap().count;
+        let (stake, _) = stake_address(&owner, &self.mint, index);
+        let (stake_token, _) =
+            Pubkey::find_program_address(&[STAKE_TOKEN_ACCOUNT_SEED, stake.as_ref()], &staking::id());
+
+        let instruction = Instruction {
+            program_id: staking::id(),
+            accounts: accounts::CreateNftStakeAccount {
+                positions,
+                stake,
+                escrow: self.escrow,
+                owner,
+                mint: self.mint,
+                nft_mint,
+                nft_metadata,
+                stake_token,
+                owner_nft_token,
+                token_program: self.token_program,
+                system_program: system_program::id(),
+                rent: solana_sdk::sysvar::rent::id(),
+            }.to_account_metas(None),
+            data: instruction::CreateNftStakeAccount { lock_tier }.data(),
+        };
+        (stake, self.process(&[instruction]).await)
+    }
+
+    async fn try_close_nft_stake(&mut self, stake: Pubkey, owner_nft_token: Pubkey) -> Result<(), BanksClientError> {
+        let Stake { nft_mint, stake_token, .. } = self.stake(stake).await;
+        let owner = self.ctx.payer.pubkey();
+        let instruction = Instruction {
+            program_id: staking::id(),
+            accounts: accounts::CloseNftStakeAccount {
+                stake,
+                escrow: self.escrow,
+                owner,
+                mint: self.mint,
+                nft_mint,
+                reward_mint: self.reward_mint,
+                stake_token,
+                owner_nft_token,
+                reward_token: self.reward_token,
+                owner_reward_token: self.owner_token,
+                token_program: self.token_program,
+            }.to_account_metas(None),
+            data: instruction::CloseNftStakeAccount {}.data(),
+        };
+        self.process(&[instruction]).await
+    }
+
+    async fn update_reward_rate(&mut self, payer: Option<&Keypair>, reward_rate: u64) -> Result<(), BanksClientError> {
+        let instruction = Instruction {
+            program_id: staking::id(),
+            accounts: accounts::UpdateEscrowAccount {
+                escrow: self.escrow,
+                payer: payer.map_or(self.ctx.payer.pubkey(), |p| p.pubkey()),
+                mint: self.mint,
+            }.to_account_metas(None),
+            data: instruction::UpdateRewardRate { reward_rate }.data(),
+        };
+        let mut transaction = Transaction::new_with_payer(&[instruction], Some(&self.ctx.payer.pubkey()));
+        match payer {
+            Some(payer) => transaction.sign(&[&self.ctx.payer, payer], self.ctx.last_blockhash),
+            None => transaction.sign(&[&self.ctx.payer], self.ctx.last_blockhash),
+        }
+        self.ctx.banks_client.process_transaction(transaction).await
+    }
+
+    async fn update_unbonding_period(&mut self, unbonding_period: u64) {
+        let instruction = Instruction {
+            program_id: staking::id(),
+            accounts: accounts::UpdateEscrowAccount {
+                escrow: self.escrow,
+                payer: self.ctx.payer.pubkey(),
+                mint: self.mint,
+            }.to_account_metas(None),
+            data: instruction::UpdateUnbondingPeriod { unbonding_period }.data(),
+        };
+        self.process(&[instruction]).await.unwrap();
+    }
+
+    fn vault_addresses(&self) -> (Pubkey, Pubkey) {
+        let (vault_stake, _) =
+            Pubkey::find_program_address(&[VAULT_STAKE_ACCOUNT_SEED, self.escrow.as_ref()], &staking::id());
+        let (share_mint, _) = Pubkey::find_program_address(&[SHARE_MINT_SEED, self.escrow.as_ref()], &staking::id());
+        (vault_stake, share_mint)
+    }
+
+    /// Creates the vault of the pool and returns a share token account of the payer.
+    async fn create_vault(&mut self) -> Pubkey {
+        let payer = self.ctx.payer.pubkey();
+        let (vault_stake, share_mint) = self.vault_addresses();
+        let instruction = Instruction {
+            program_id: staking::id(),
+            accounts: accounts::CreateVault {
+                escrow: self.escrow,
+                vault_stake,
+                payer,
+                mint: self.mint,
+                share_mint,
+                token_program: self.token_program,
+                system_program: system_program::id(),
+            }.to_account_metas(None),
+            data: instruction::CreateVault {}.data(),
+        };
+        self.process(&[instruction]).await.unwrap();
+
+        let token_program = self.token_program;
+        create_token_account(&mut self.ctx, &token_program, &share_mint).await
+    }
+
+    async fn deposit_vault(&mut self, owner_share_token: Pubkey, amount: u64) -> Result<(), BanksClientError> {
+        let (vault_stake, share_mint) = self.vault_addresses();
+        let instruction = Instruction {
+            program_id: staking::id(),
+            accounts: accounts::DepositVault {
+                escrow: self.escrow,
+                vault_stake,
+                owner: self.ctx.payer.pubkey(),
+                mint: self.mint,
+                share_mint,
+                escrow_token: self.escrow_token,
+                reward_token: self.reward_token,
+                owner_token: self.owner_token,
+                owner_share_token,
+                token_program: self.token_program,
+            }.to_account_metas(None),
+            data: instruction::DepositVault { amount }.data(),
+        };
+        self.process(&[instruction]).await
+    }
+
+    async fn withdraw_vault(&mut self, owner_share_token: Pubkey, shares: u64) -> Result<(), BanksClientError> {
+        let (vault_stake, share_mint) = self.vault_addresses();
+        let instruction = Instruction {
+            program_id: staking::id(),
+            accounts: accounts::WithdrawVault {
+                escrow: self.escrow,
+                vault_stake,
+                owner: self.ctx.payer.pubkey(),
+                mint: self.mint,
+                share_mint,
+                escrow_token: self.escrow_token,
+                reward_token: self.reward_token,
+                owner_token: self.owner_token,
+                owner_share_token,
+                token_program: self.token_program,
+            }.to_account_metas(None),
+            data: instruction::WithdrawVault { shares }.data(),
+        };
+        self.process(&[instruction]).await
+    }
+
+    async fn escrow(&mut self) -> Escrow {
+        let account = self.ctx.banks_client.get_account(self.escrow).await.unwrap().unwrap();
+        Escrow::try_deserialize(&mut account.data.as_slice()).unwrap()
+    }
+
+    async fn stake(&mut self, stake: Pubkey) -> Stake {
+        let account = self.ctx.banks_client.get_account(stake).await.unwrap().unwrap();
+        Stake::try_deserialize(&mut account.data.as_slice()).unwrap()
+    }
+
+    async fn token_amount(&mut self, token: Pubkey) -> u64 {
+        let account = self.ctx.banks_client.get_account(token).await.unwrap().unwrap();
+        StateWithExtensions::<TokenAccount>::unpack(&account.data).unwrap().base.amount
+    }
+
+    /// Adds assets to the vault without minting any shares for them.
+    async fn set_vault_leftover(&mut self, amount: u64) {
+        let (vault_stake, _) = self.vault_addresses();
+        let mut stake = self.stake(vault_stake).await;
+        let mut escrow = self.escrow().await;
+        stake.set_amount(&mut escrow, stake.amount + amount).unwrap();
+        self.set_anchor_account(vault_stake, &stake).await;
+        let escrow_key = self.escrow;
+        self.set_anchor_account(escrow_key, &escrow).await;
+
+        let escrow_token = self.escrow_token;
+        let balance = self.token_amount(escrow_token).await;
+        self.set_token_amount(escrow_token, balance + amount).await;
+    }
+
+    async fn set_anchor_account<T: AccountSerialize>(&mut self, key: Pubkey, value: &T) {
+        let mut account = self.ctx.banks_client.get_account(key).await.unwrap().unwrap();
+        let mut data = Vec::new();
+        value.try_serialize(&mut data).unwrap();
+        account.data[..data.len()].copy_from_slice(&data);
+        self.ctx.set_account(&key, &AccountSharedData::from(account));
+    }
+
+    /// Overwrites the balance of a token account, as if tokens had been lost or recovered.
+    async fn set_token_amount(&mut self, token: Pubkey, amount: u64) {
+        let mut account = self.ctx.banks_client.get_account(token).await.unwrap().unwrap();
+        let mut state = StateWithExtensionsMut::<TokenAccount>::unpack(&mut account.data).unwrap();
+        state.base.amount = amount;
+        state.pack_base();
+        self.ctx.set_account(&token, &AccountSharedData::from(account));
+    }
+}
+
+async fn start() -> ProgramTestContext {
+    let mut pt = ProgramTest::new("staking", staking::id(), processor!(staking::entry));
+    pt.add_program(
+        "spl_token_2022",
+        spl_token_2022::id(),
+        processor!(spl_token_2022::processor::Processor::process),
+    );
+    pt.start_with_context().await
+}
+
+/// Creates a mint of `token_program` without decimals and a token account of the payer holding the whole `supply`.
+/// The mint charges `transfer_fee_bps` of every transfer if not 0, which is only supported by token-2022.
+async fn create_mint_and_token(
+    ctx: &mut ProgramTestContext,
+    token_program: &Pubkey,
+    transfer_fee_bps: u16,
+    supply: u64,
+) -> (Pubkey, Pubkey) {
+    let mint = Keypair::new();
+    let token = Keypair::new();
+    let payer = ctx.payer.pubkey();
+    let rent: Rent = ctx.banks_client.get_sysvar().await.unwrap();
+
+    let mint_extensions = if transfer_fee_bps > 0 {
+        vec![ExtensionType::TransferFeeConfig]
+    } else {
+        vec![]
+    };
+    let mint_len = ExtensionType::get_account_len::<Mint>(&mint_extensions);
+    let token_len = ExtensionType::get_account_len::<TokenAccount>(
+        &ExtensionType::get_required_init_account_extensions(&mint_extensions),
+    );
+
+    // Token-2022 encodes the instructions of the token program identically.
+    let token_instruction = |mut instruction: Instruction| {
+        instruction.program_id = *token_program;
+        instruction
+    };
+    let mut instructions = vec![system_instruction::create_account(
+        &payer,
+        &mint.pubkey(),
+        rent.minimum_balance(mint_len),
+        mint_len as u64,
+        token_program,
+    )];
+    if transfer_fee_bps > 0 {
+        instructions.push(
+            transfer_fee::instruction::initialize_transfer_fee_config(
+                token_program,
+                &mint.pubkey(),
+                Some(&payer),
+                Some(&payer),
+                transfer_fee_bps,
+                u64::MAX,
+            )
+            .unwrap(),
+        );
+    }
+    instructions.extend([
+        token_instruction(
+            spl_token::instruction::initialize_mint(&spl_token::id(), &mint.pubkey(), &payer, None, 0).unwrap(),
+        ),
+        system_instruction::create_account(
+            &payer,
+            &token.pubkey(),
+            rent.minimum_balance(token_len),
+            token_len as u64,
+            token_program,
+        ),
+        token_instruction(
+            spl_token::instruction::initialize_account(&spl_token::id(), &token.pubkey(), &mint.pubkey(), &payer)
+                .unwrap(),
+        ),
+        token_instruction(
+            spl_token::instruction::mint_to(&spl_token::id(), &mint.pubkey(), &token.pubkey(), &payer, &[], supply)
+                .unwrap(),
+        ),
+    ]);
+    let mut transaction = Transaction::new_with_payer(&instructions, Some(&payer));
+    transaction.sign(&[&ctx.payer, &mint, &token], ctx.last_blockhash);
+    ctx.banks_client.process_transaction(transaction).await.unwrap();
+
+    (mint.pubkey(), token.pubkey())
+}
+
+/// Creates an empty token account of the payer for a mint of `token_program` without extensions.
+async fn create_token_account(ctx: &mut ProgramTestContext, token_program: &Pubkey, mint: &Pubkey) -> Pubkey {
+    let token = Keypair::new();
+    let payer = ctx.payer.pubkey();
+    let rent: Rent = ctx.banks_client.get_sysvar().await.unwrap();
+    let token_len = ExtensionType::get_account_len::<TokenAccount>(&[]);
+
+    let mut initialize_account =
+        spl_token::instruction::initialize_account(&spl_token::id(), &token.pubkey(), mint, &payer).unwrap();
+    initialize_account.program_id = *token_program;
+    let instructions = [
+        system_instruction::create_account(
+            &payer,
+            &token.pubkey(),
+            rent.minimum_balance(token_len),
+            token_len as u64,
+            token_program,
+        ),
+        initialize_account,
+    ];
+    let mut transaction = Transaction::new_with_payer(&instructions, Some(&payer));
+    transaction.sign(&[&ctx.payer, &token], ctx.last_blockhash);
+    ctx.banks_client.process_transaction(transaction).await.unwrap();
+
+    token.pubkey()
+}
+
+fn assert_error<T: std::fmt::Debug + PartialEq>(result: anchor_lang::Result<T>, error: StakingError) {
+    assert_eq!(
+        result.map_err(ProgramError::from),
+        Err(ProgramError::Custom(ERROR_CODE_OFFSET + error as u32))
+    );
+}
+
+fn assert_staking_error(result: Result<(), BanksClientError>, error: StakingError) {
+    assert_matches!(
+        result,
+        Err(BanksClientError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(code),
+        ))) if code == ERROR_CODE_OFFSET + error as u32
+    );
+}
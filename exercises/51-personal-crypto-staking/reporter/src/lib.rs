@@ -0,0 +1,172 @@
+//! Reporter of the positions of the staking program.
+//!
+//! Positions are read by simulating the read-only `view_position` instruction, so that the pending rewards are
+//! computed by the program itself at the current time of the pool, and summarized per mint.
+
+use anchor_lang::{AccountDeserialize, AnchorDeserialize, Discriminator, InstructionData, ToAccountMetas};
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::{
+    client_error::ClientError,
+    rpc_client::RpcClient,
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcSimulateTransactionConfig},
+    rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
+};
+use solana_sdk::{
+    instruction::Instruction,
+    message::Message,
+    pubkey::Pubkey,
+    transaction::Transaction,
+};
+use spl_token_2022::{extension::StateWithExtensions, state::Account as TokenAccount};
+use staking::{accounts, instruction, Escrow, PositionView, Stake, ESCROW_ACCOUNT_SEED};
+use thiserror::Error;
+
+// Offset of the mint in a stake account, after the discriminator and the owner.
+const STAKE_MINT_OFFSET: usize = 8 + 32;
+
+#[derive(Debug, Error)]
+pub enum ReporterError {
+    #[error("rpc error: {0}")]
+    Rpc(#[from] ClientError),
+    #[error("simulation failed: {0}")]
+    Simulation(String),
+    #[error("simulation returned no position")]
+    MissingReturnData,
+    #[error("invalid account {0}")]
+    InvalidAccount(Pubkey),
+}
+
+pub type Result<T> = std::result::Result<T, ReporterError>;
+
+pub fn escrow_address(mint: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[ESCROW_ACCOUNT_SEED, mint.as_ref()], &staking::id()).0
+}
+
+pub fn view_position_instruction(stake: &Pubkey, mint: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: staking::id(),
+        accounts: accounts::ViewPosition {
+            stake: *stake,
+            escrow: escrow_address(mint),
+            mint: *mint,
+        }.to_account_metas(None),
+        data: instruction::ViewPosition {}.data(),
+    }
+}
+
+/// Returns the data returned by the program in the logs of a transaction, as logged by the runtime.
+pub fn parse_return_data(logs: &[String], program_id: &Pubkey) -> Option<Vec<u8>> {
+    let prefix = format!("Program return: {} ", program_id);
+    logs.iter()
+        .rev()
+        .find_map(|log| log.strip_prefix(&prefix))
+        .and_then(|data| base64::decode(data).ok())
+}
+
+/// Simulates `view_position` for the stake. The fee payer only has to exist, nothing is signed nor paid.
+pub fn view_position(rpc: &RpcClient, fee_payer: &Pubkey, stake: &Pubkey, mint: &Pubkey) -> Result<PositionView> {
+    let message = Message::new(&[view_position_instruction(stake, mint)], Some(fee_payer));
+    let mut transaction = Transaction::new_unsigned(message);
+    transaction.message.recent_blockhash = rpc.get_latest_blockhash()?;
+
+    let config = RpcSimulateTransactionConfig {
+        sig_verify: false,
+        ..RpcSimulateTransactionConfig::default()
+    };
+    let result = rpc.simulate_transaction_with_config(&transaction, config)?.value;
+    if let Some(err) = result.err {
+        return Err(ReporterError::Simulation(err.to_string()));
+    }
+
+    let data = parse_return_data(&result.logs.unwrap_or_default(), &staking::id())
+        .ok_or(ReporterError::MissingReturnData)?;
+    PositionView::try_from_slice(&data).map_err(|_| ReporterError::MissingReturnData)
+}
+
+pub fn stake_account(rpc: &RpcClient, stake: &Pubkey) -> Result<Stake> {
+    let account = rpc.get_account(stake)?;
+    Stake::try_deserialize(&mut account.data.as_slice()).map_err(|_| ReporterError::InvalidAccount(*stake))
+}
+
+/// Returns all the stake accounts of the mint, including the stake of the vault of the pool if it has one.
+pub fn stake_accounts(rpc: &RpcClient, mint: &Pubkey) -> Result<Vec<(Pubkey, Stake)>> {
+    let config = RpcProgramAccountsConfig {
+        filters: Some(vec![
+            RpcFilterType::Memcmp(Memcmp {
+                offset: 0,
+                bytes: MemcmpEncodedBytes::Base58(bs58::encode(Stake::discriminator()).into_string()),
+                encoding: None,
+            }),
+            RpcFilterType::Memcmp(Memcmp {
+                offset: STAKE_MINT_OFFSET,
+                bytes: MemcmpEncodedBytes::Base58(mint.to_string()),
+                encoding: None,
+            }),
+        ]),
+        account_config: RpcAccountInfoConfig {
+            encoding: Some(UiAccountEncoding::Base64),
+            ..RpcAccountInfoConfig::default()
+        },
+        ..RpcProgramAccountsConfig::default()
+    };
+
+    rpc.get_program_accounts_with_config(&staking::id(), config)?
+        .into_iter()
+        .map(|(key, account)| {
+            Stake::try_deserialize(&mut account.data.as_slice())
+                .map(|stake| (key, stake))
+                .map_err(|_| ReporterError::InvalidAccount(key))
+        })
+        .collect()
+}
+
+/// Summary of the positions of a mint.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub positions: u64,
+    pub total_staked: u128,
+    pub total_unbonding: u128,
+    // Rewards accrued by the positions and not paid yet, which the reward vault owes to the stakes.
+    pub outstanding_rewards: u128,
+    pub reward_balance: u64,
+}
+
+impl Report {
+    pub fn add(&mut self, view: &PositionView) {
+        self.positions += 1;
+        self.total_staked += view.amount as u128;
+        self.total_unbonding += view.unbonding_amount as u128;
+        self.outstanding_rewards += view.reward as u128;
+    }
+
+    /// Total value locked in the pool, staked or unbonding.
+    pub fn tvl(&self) -> u128 {
+        self.total_staked + self.total_unbonding
+    }
+
+    /// Whether the reward vault holds enough to pay all the outstanding rewards.
+    pub fn is_covered(&self) -> bool {
+        self.reward_balance as u128 >= self.outstanding_rewards
+    }
+}
+
+/// Simulates `view_position` for every stake of the mint and summarizes them.
+pub fn report(rpc: &RpcClient, fee_payer: &Pubkey, mint: &Pubkey) -> Result<Report> {
+    let escrow_key = escrow_address(mint);
+    let account = rpc.get_account(&escrow_key)?;
+    let escrow = Escrow::try_deserialize(&mut account.data.as_slice())
+        .map_err(|_| ReporterError::InvalidAccount(escrow_key))?;
+    // Token-2022 accounts share the layout of the token program accounts, followed by their extensions.
+    let account = rpc.get_account(&escrow.reward_token)?;
+    let reward_token = StateWithExtensions::<TokenAccount>::unpack(&account.data)
+        .map_err(|_| ReporterError::InvalidAccount(escrow.reward_token))?;
+
+    let mut report = Report {
+        reward_balance: reward_token.base.amount,
+        ..Report::default()
+    };
+    for (key, _) in stake_accounts(rpc, mint)? {
+        report.add(&view_position(rpc, fee_payer, &key, mint)?);
+    }
+    Ok(report)
+}
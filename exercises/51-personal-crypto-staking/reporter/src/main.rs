@@ -0,0 +1,64 @@
+use std::error::Error;
+use std::str::FromStr;
+
+use clap::{Parser, Subcommand};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey};
+use staking_reporter::{report, stake_account, view_position};
+
+#[derive(Parser)]
+#[clap(about = "Reporter of the positions of the staking program")]
+struct Cli {
+    #[clap(long, default_value = "http://localhost:8899")]
+    url: String,
+    /// Existing account used as the fee payer of the simulations. Nothing is signed nor paid.
+    #[clap(long, parse(try_from_str = Pubkey::from_str))]
+    fee_payer: Pubkey,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the current principal, pending reward and unlock time of a stake.
+    View {
+        #[clap(parse(try_from_str = Pubkey::from_str))]
+        stake: Pubkey,
+    },
+    /// Summarize the value locked and the outstanding rewards of all the stakes of a mint.
+    Report {
+        #[clap(parse(try_from_str = Pubkey::from_str))]
+        mint: Pubkey,
+    },
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    let rpc = RpcClient::new_with_commitment(cli.url, CommitmentConfig::confirmed());
+
+    match cli.command {
+        Command::View { stake } => {
+            let mint = stake_account(&rpc, &stake)?.mint;
+            let view = view_position(&rpc, &cli.fee_payer, &stake, &mint)?;
+            println!("Principal: {}", view.amount);
+            println!("Pending reward: {}", view.reward);
+            println!("Unlock at: {}", view.unlock_at);
+            if view.unbonding_amount > 0 {
+                println!("Unbonding: {} until {}", view.unbonding_amount, view.unbonding_end_at);
+            }
+        }
+        Command::Report { mint } => {
+            let report = report(&rpc, &cli.fee_payer, &mint)?;
+            println!("Positions: {}", report.positions);
+            println!("Total value locked: {}", report.tvl());
+            println!("  staked: {}", report.total_staked);
+            println!("  unbonding: {}", report.total_unbonding);
+            println!("Outstanding rewards: {}", report.outstanding_rewards);
+            println!("Reward vault balance: {}", report.reward_balance);
+            if !report.is_covered() {
+                println!("Warning: the reward vault does not cover the outstanding rewards");
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use solana_sdk::pubkey::Pubkey;
+use staking::PositionView;
+use staking_reporter::{parse_return_data, Report};
+
+#[test]
+fn test_parse_return_data() {
+    let program_id = staking::id();
+    let logs = vec![
+        format!("Program {} invoke [1]", program_id),
+        "Program log: Instruction: ViewPosition".to_string(),
+        format!("Program return: {} AQIDBA==", program_id),
+        format!("Program {} success", program_id),
+    ];
+    assert_eq!(parse_return_data(&logs, &program_id), Some(vec![1, 2, 3, 4]));
+
+    // Data returned by other programs is ignored.
+    let logs = vec![format!("Program return: {} AQIDBA==", Pubkey::new_unique())];
+    assert_eq!(parse_return_data(&logs, &program_id), None);
+}
+
+#[test]
+fn test_report() {
+    let mut report = Report {
+        reward_balance: 100,
+        ..Report::default()
+    };
+    report.add(&PositionView {
+        amount: 1000,
+        reward: 60,
+        ..PositionView::default()
+    });
+    report.add(&PositionView {
+        amount: u64::MAX,
+        reward: 30,
+        unbonding_amount: 500,
+        ..PositionView::default()
+    });
+
+    assert_eq!(report.positions, 2);
+    assert_eq!(report.tvl(), u64::MAX as u128 + 1500);
+    assert_eq!(report.outstanding_rewards, 90);
+    assert!(report.is_covered());
+
+    report.add(&PositionView {
+        reward: 20,
+        ..PositionView::default()
+    });
+    assert!(!report.is_covered());
+}
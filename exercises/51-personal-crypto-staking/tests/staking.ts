@@ -16,32 +16,58 @@ describe("staking", () => {
   let escrow = web3.PublicKey.default;
   let escrowBump = -1;
   let escrowToken = web3.PublicKey.default;
+  let rewardToken = web3.PublicKey.default;
 
   const owner = provider.wallet.publicKey;
   let ownerToken: web3.PublicKey = web3.PublicKey.default;
 
-  const getStakePublicKey = async () => {
+  const getStakePublicKey = async (index: number) => {
     return await web3.PublicKey.findProgramAddress(
-      [Buffer.from("stake"), owner.toBuffer(), mint.toBuffer()],
+      [Buffer.from("stake"), owner.toBuffer(), mint.toBuffer(), new BN(index).toArrayLike(Buffer, "le", 8)],
       program.programId,
     );
   };
 
+  const getPositionsPublicKey = async () => {
+    return await web3.PublicKey.findProgramAddress(
+      [Buffer.from("positions"), owner.toBuffer(), mint.toBuffer()],
+      program.programId,
+    );
+  };
+
+  // Enumerates the open stake positions of the owner for the mint.
+  const fetchStakePositions = async () => {
+    const [positions,] = await getPositionsPublicKey();
+    const positionsAccount = await program.account.positions.fetch(positions);
+    const stakes = [];
+    for (let index = 0; index < positionsAccount.count.toNumber(); index++) {
+      const [stake,] = await getStakePublicKey(index);
+      stakes.push(stake);
+    }
+    const stakeAccounts = await program.account.stake.fetchMultiple(stakes);
+    return stakes
+      .map((stake, index) => ({ publicKey: stake, account: stakeAccounts[index] }))
+      .filter(({ account }) => account != null);
+  };
+
   it("Initializes test state", async () => {
     mint = await createMint(provider);
     [escrow, escrowBump] = getEscrowPublicKey(program.programId, mint);
-    escrowToken = await createAssociatedTokenAccount(provider, mint, escrow);
+    [escrowToken,] = getEscrowTokenPublicKey(program.programId, "escrow_token", escrow);
+    [rewardToken,] = getEscrowTokenPublicKey(program.programId, "reward_token", escrow);
     ownerToken = await createAssociatedTokenAccount(provider, mint, provider.wallet.publicKey);
     await mintTo(provider, mint, ownerToken, 1e10);
   });
 
   it("Creates and funds escrow account", async () => {
-    await program.rpc.createEscrowAccount({
+    await program.rpc.createEscrowAccount({ unixTimestamp: {} }, {
       accounts: {
         escrow,
         payer: provider.wallet.publicKey,
         mint,
+        rewardMint: mint,
         escrowToken,
+        rewardToken,
         tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
         systemProgram: web3.SystemProgram.programId,
       },
@@ -51,21 +77,31 @@ describe("staking", () => {
     assert.equal(escrowAccount.payer.toString(), provider.wallet.publicKey.toString());
     assert.equal(escrowAccount.mint.toString(), mint.toString());
     assert.equal(escrowAccount.bump, escrowBump);
+    assert.equal(escrowAccount.escrowToken.toString(), escrowToken.toString());
+    assert.equal(escrowAccount.rewardMint.toString(), mint.toString());
+    assert.equal(escrowAccount.rewardToken.toString(), rewardToken.toString());
 
-    await program.rpc.fundEscrowAccount(new anchor.BN(1e9), {
+    // Rewards are emitted at 20 tokens per second once something is staked.
+    await program.rpc.fundEscrowAccount(new anchor.BN(1e9), new anchor.BN(5e7), {
       accounts: {
         escrow,
         payer: provider.wallet.publicKey,
         mint,
-        escrowToken,
+        rewardMint: mint,
+        rewardToken,
         payerToken: ownerToken,
         tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
       },
     });
 
+    // Rewards are kept separate from the stakes, even when both are of the same mint.
     const escrowTokenAccount = await fetchTokenAccount(provider, escrowToken);
     assert.ok(escrowTokenAccount != null);
-    assert.ok(escrowTokenAccount.amount === BigInt(1e9));
+    assert.equal(escrowTokenAccount.amount, BigInt(0));
+
+    const rewardTokenAccount = await fetchTokenAccount(provider, rewardToken);
+    assert.ok(rewardTokenAccount != null);
+    assert.equal(rewardTokenAccount.amount, BigInt(1e9));
 
     const ownerTokenAccount = await fetchTokenAccount(provider, ownerToken);
     assert.ok(ownerTokenAccount != null);
@@ -75,9 +111,26 @@ describe("staking", () => {
   let stakeDoneTime = 0;
 
   it("Stakes tokens", async () => {
-    const [stake, bump] = await getStakePublicKey();
-    await program.rpc.createStakeAccount(new anchor.BN(1e9), {
+    const [positions, positionsBump] = await getPositionsPublicKey();
+    await program.rpc.createPositionsAccount({
+      accounts: {
+        positions,
+        owner,
+        mint,
+        systemProgram: web3.SystemProgram.programId,
+      },
+    });
+
+    const positionsAccount = await program.account.positions.fetch(positions);
+    assert.equal(positionsAccount.owner.toString(), owner.toString());
+    assert.equal(positionsAccount.mint.toString(), mint.toString());
+    assert.ok(positionsAccount.count.eq(new anchor.BN(0)));
+    assert.equal(positionsAccount.bump, positionsBump);
+
+    const [stake, bump] = await getStakePublicKey(0);
+    await program.rpc.createStakeAccount(new anchor.BN(1e9), 0, {
       accounts: {
+        positions,
         stake,
         escrow,
         mint,
@@ -94,21 +147,473 @@ describe("staking", () => {
     const stakeAccount = await program.account.stake.fetch(stake);
     assert.equal(stakeAccount.owner.toString(), owner.toString());
     assert.equal(stakeAccount.mint.toString(), mint.toString());
+    assert.ok(stakeAccount.index.eq(new anchor.BN(0)));
     assert.ok(stakeAccount.amount.eq(new anchor.BN(1e9)));
     assert.equal(stakeAccount.bump, bump);
     assert.ok(stakeAccount.createdAt.gt(new anchor.BN(0)));
+    assert.ok(stakeAccount.unlockAt.eq(stakeAccount.createdAt));
+    assert.equal(stakeAccount.multiplierBps, 10000);
+    assert.ok(stakeAccount.weight.eq(new anchor.BN(1e9)));
+
+    const escrowTokenAccount = await fetchTokenAccount(provider, escrowToken);
+    assert.ok(escrowTokenAccount != null);
+    assert.equal(escrowTokenAccount.amount, BigInt(1e9));
+  });
+
+  it("Manages the stake", async () => {
+    const [stake,] = await getStakePublicKey(0);
+    await program.rpc.addStake(new anchor.BN(1e9), {
+      accounts: {
+        stake,
+        escrow,
+        owner,
+        mint,
+        escrowToken,
+        ownerToken,
+        tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+      },
+    });
+
+    let stakeAccount = await program.account.stake.fetch(stake);
+    assert.ok(stakeAccount.amount.eq(new anchor.BN(2e9)));
+
+    await program.rpc.partialUnstake(new anchor.BN(5e8), {
+      accounts: {
+        stake,
+        escrow,
+        owner,
+        mint,
+        escrowToken,
+        ownerToken,
+        tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+      },
+    });
+
+    stakeAccount = await program.account.stake.fetch(stake);
+    assert.ok(stakeAccount.amount.eq(new anchor.BN(15e8)));
+
+    const escrowTokenAccount = await fetchTokenAccount(provider, escrowToken);
+    assert.ok(escrowTokenAccount != null);
+    assert.equal(escrowTokenAccount.amount, BigInt(15e8));
+
+    await program.rpc.claimRewards({
+      accounts: {
+        stake,
+        escrow,
+        owner,
+        mint,
+        rewardMint: mint,
+        rewardToken,
+        ownerRewardToken: ownerToken,
+        tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+      },
+    });
+
+    stakeAccount = await program.account.stake.fetch(stake);
+    assert.ok(stakeAccount.reward.eq(new anchor.BN(0)));
+
+    // The position can be viewed without modifying it by simulating view_position.
+    const simulation = await program.simulate.viewPosition({
+      accounts: {
+        stake,
+        escrow,
+        mint,
+      },
+    });
+    const returnPrefix = `Program return: ${program.programId} `;
+    const returnLog = simulation.raw.find((log) => log.startsWith(returnPrefix));
+    assert.ok(returnLog != null);
+    const returnData = Buffer.from(returnLog.slice(returnPrefix.length), "base64");
+    const view = program.coder.types.decode("PositionView", returnData);
+    assert.ok(view.amount.eq(new anchor.BN(15e8)));
+    assert.ok(view.unlockAt.eq(stakeAccount.unlockAt));
+
+    const rewardTokenAccount = await fetchTokenAccount(provider, rewardToken);
+    assert.ok(rewardTokenAccount != null);
+    assert.ok(rewardTokenAccount.amount < BigInt(1e9));
+  });
+
+  it("Stakes tokens in another position", async () => {
+    const [positions,] = await getPositionsPublicKey();
+    const [stake,] = await getStakePublicKey(1);
+    await program.rpc.createStakeAccount(new anchor.BN(5e8), 0, {
+      accounts: {
+        positions,
+        stake,
+        escrow,
+        mint,
+        owner,
+        escrowToken,
+        ownerToken,
+        tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      },
+    });
+
+    const positionsAccount = await program.account.positions.fetch(positions);
+    assert.ok(positionsAccount.count.eq(new anchor.BN(2)));
 
+    const stakePositions = await fetchStakePositions();
+    assert.equal(stakePositions.length, 2);
+    assert.ok(stakePositions[0].account.amount.eq(new anchor.BN(15e8)));
+    assert.equal(stakePositions[1].publicKey.toString(), stake.toString());
+    assert.ok(stakePositions[1].account.index.eq(new anchor.BN(1)));
+    assert.ok(stakePositions[1].account.amount.eq(new anchor.BN(5e8)));
+  });
+
+  it("Locks stakes", async () => {
+    const [positions,] = await getPositionsPublicKey();
+    const [stake,] = await getStakePublicKey(2);
+    // The 30 days tier boosts the rewards by 25%.
+    await program.rpc.createStakeAccount(new anchor.BN(1e9), 1, {
+      accounts: {
+        positions,
+        stake,
+        escrow,
+        mint,
+        owner,
+        escrowToken,
+        ownerToken,
+        tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      },
+    });
+
+    const stakeAccount = await program.account.stake.fetch(stake);
+    assert.ok(stakeAccount.unlockAt.eq(stakeAccount.createdAt.add(new anchor.BN(30 * 24 * 60 * 60))));
+    assert.equal(stakeAccount.multiplierBps, 12500);
+    assert.ok(stakeAccount.weight.eq(new anchor.BN(125e7)));
+
+    const closeStakeAccount = async () => {
+      await program.rpc.closeStakeAccount({
+        accounts: {
+          stake,
+          escrow,
+          mint,
+          rewardMint: mint,
+          owner,
+          escrowToken,
+          ownerToken,
+          rewardToken,
+          ownerRewardToken: ownerToken,
+          tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+        },
+      });
+    };
+
+    // Closing a locked stake is rejected without an early withdrawal penalty.
+    let closed = true;
+    try {
+      await closeStakeAccount();
+    } catch (e: any) {
+      closed = false;
+      assert.equal(e?.code, 6009);
+    }
+    assert.ok(!closed);
+
+    const escrowAccount = await program.account.escrow.fetch(escrow);
+    await program.rpc.updateLockTiers(escrowAccount.lockTiers, 1000, {
+      accounts: {
+        escrow,
+        payer: provider.wallet.publicKey,
+        mint,
+      },
+    });
+
+    // With a 10% penalty, the penalty is moved to the rewards of the other stakes.
     const escrowTokenAccount = await fetchTokenAccount(provider, escrowToken);
     assert.ok(escrowTokenAccount != null);
-    assert.equal(escrowTokenAccount.amount, BigInt(2e9));
+    await closeStakeAccount();
+    const newEscrowTokenAccount = await fetchTokenAccount(provider, escrowToken);
+    assert.ok(newEscrowTokenAccount != null);
+    assert.equal(newEscrowTokenAccount.amount, escrowTokenAccount.amount - BigInt(1e9));
+  });
+
+  it("Unbonds stakes", async () => {
+    const updateUnbondingPeriod = async (unbondingPeriod: number) => {
+      await program.rpc.updateUnbondingPeriod(new anchor.BN(unbondingPeriod), {
+        accounts: {
+          escrow,
+          payer: provider.wallet.publicKey,
+          mint,
+        },
+      });
+    };
+    await updateUnbondingPeriod(2);
+
+    const [stake,] = await getStakePublicKey(1);
+    await program.rpc.requestUnstake(new anchor.BN(5e8), {
+      accounts: {
+        stake,
+        escrow,
+        owner,
+        mint,
+        escrowToken,
+        rewardToken,
+        tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+      },
+    });
+    const requestTime = Date.now();
+
+    const stakeAccount = await program.account.stake.fetch(stake);
+    assert.ok(stakeAccount.amount.eq(new anchor.BN(0)));
+    assert.ok(stakeAccount.weight.eq(new anchor.BN(0)));
+    assert.ok(stakeAccount.unbondingAmount.eq(new anchor.BN(5e8)));
+
+    let escrowAccount = await program.account.escrow.fetch(escrow);
+    assert.ok(escrowAccount.totalUnbonding.eq(new anchor.BN(5e8)));
+
+    // A second request is rejected until the first one is completed, so that it cannot delay the unbonding amount.
+    const requestUnstake = async () => {
+      let requested = true;
+      try {
+        await program.rpc.requestUnstake(new anchor.BN(1), {
+          accounts: {
+            stake,
+            escrow,
+            owner,
+            mint,
+            escrowToken,
+            rewardToken,
+            tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+          },
+        });
+      } catch (e: any) {
+        requested = false;
+        assert.equal(e?.code, 6011);
+      }
+      assert.ok(!requested);
+    };
+    await requestUnstake();
+
+    const completeUnstake = async () => {
+      await program.rpc.completeUnstake({
+        accounts: {
+          stake,
+          escrow,
+          owner,
+          mint,
+          escrowToken,
+          ownerToken,
+          tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+        },
+      });
+    };
+
+    // The unbonding amount can only be withdrawn after the unbonding period.
+    let completed = true;
+    try {
+      await completeUnstake();
+    } catch (e: any) {
+      completed = false;
+      assert.equal(e?.code, 6011);
+    }
+    assert.ok(!completed);
+
+    const ownerTokenAccount = await fetchTokenAccount(provider, ownerToken);
+    assert.ok(ownerTokenAccount != null);
+
+    await sleep(3000 - Date.now() + requestTime);
+    await requestUnstake();
+    await completeUnstake();
+
+    const newOwnerTokenAccount = await fetchTokenAccount(provider, ownerToken);
+    assert.ok(newOwnerTokenAccount != null);
+    assert.equal(newOwnerTokenAccount.amount, ownerTokenAccount.amount + BigInt(5e8));
+
+    escrowAccount = await program.account.escrow.fetch(escrow);
+    assert.ok(escrowAccount.totalUnbonding.eq(new anchor.BN(0)));
+
+    await updateUnbondingPeriod(0);
+  });
+
+  it("Administers the pool", async () => {
+    const setPaused = async (paused: boolean) => {
+      await program.rpc.setPaused(paused, {
+        accounts: {
+          escrow,
+          payer: provider.wallet.publicKey,
+          mint,
+        },
+      });
+    };
+
+    // New stakes are rejected while paused.
+    await setPaused(true);
+    const [stake,] = await getStakePublicKey(0);
+    let added = true;
+    try {
+      await program.rpc.addStake(new anchor.BN(1e9), {
+        accounts: {
+          stake,
+          escrow,
+          owner,
+          mint,
+          escrowToken,
+          ownerToken,
+          tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+        },
+      });
+    } catch (e: any) {
+      added = false;
+      assert.equal(e?.code, 6015);
+    }
+    assert.ok(!added);
+    await setPaused(false);
+
+    // The authority is transferred once the new payer accepts it.
+    const otherPayer = web3.Keypair.generate();
+    await program.rpc.transferAuthority(otherPayer.publicKey, {
+      accounts: {
+        escrow,
+        payer: provider.wallet.publicKey,
+        mint,
+      },
+    });
+    await program.rpc.acceptAuthority({
+      accounts: {
+        escrow,
+        newPayer: otherPayer.publicKey,
+        mint,
+      },
+      signers: [otherPayer],
+    });
+
+    let escrowAccount = await program.account.escrow.fetch(escrow);
+    assert.equal(escrowAccount.payer.toString(), otherPayer.publicKey.toString());
+    assert.equal(escrowAccount.pendingPayer.toString(), web3.PublicKey.default.toString());
+
+    await program.rpc.transferAuthority(provider.wallet.publicKey, {
+      accounts: {
+        escrow,
+        payer: otherPayer.publicKey,
+        mint,
+      },
+      signers: [otherPayer],
+    });
+    await program.rpc.acceptAuthority({
+      accounts: {
+        escrow,
+        newPayer: provider.wallet.publicKey,
+        mint,
+      },
+    });
+
+    escrowAccount = await program.account.escrow.fetch(escrow);
+    assert.equal(escrowAccount.payer.toString(), provider.wallet.publicKey.toString());
+  });
+
+  it("Compounds rewards in the vault", async () => {
+    const [shareMint,] = getEscrowTokenPublicKey(program.programId, "share_mint", escrow);
+    const [vaultStake,] = getEscrowTokenPublicKey(program.programId, "vault_stake", escrow);
+    await program.rpc.createVault({
+      accounts: {
+        escrow,
+        vaultStake,
+        payer: provider.wallet.publicKey,
+        mint,
+        shareMint,
+        tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      },
+    });
+
+    const escrowAccount = await program.account.escrow.fetch(escrow);
+    assert.equal(escrowAccount.shareMint.toString(), shareMint.toString());
+
+    const ownerShareToken = await createAssociatedTokenAccount(provider, shareMint, owner);
+    const vaultAccounts = {
+      escrow,
+      vaultStake,
+      owner,
+      mint,
+      shareMint,
+      escrowToken,
+      rewardToken,
+      ownerToken,
+      ownerShareToken,
+      tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+    };
+
+    // The first deposit mints shares 1:1.
+    await program.rpc.depositVault(new anchor.BN(1e9), { accounts: vaultAccounts });
+    let ownerShareTokenAccount = await fetchTokenAccount(provider, ownerShareToken);
+    assert.ok(ownerShareTokenAccount != null);
+    assert.equal(ownerShareTokenAccount.amount, BigInt(1e9));
+
+    // Compounded rewards raise the share price, so later deposits get fewer shares.
+    await sleep(2000);
+    await program.rpc.depositVault(new anchor.BN(1e9), { accounts: vaultAccounts });
+    ownerShareTokenAccount = await fetchTokenAccount(provider, ownerShareToken);
+    assert.ok(ownerShareTokenAccount != null);
+    const shares = ownerShareTokenAccount.amount;
+    assert.ok(shares < BigInt(2e9));
+
+    const vaultStakeAccount = await program.account.stake.fetch(vaultStake);
+    assert.ok(vaultStakeAccount.amount.gt(new anchor.BN(2e9)));
+
+    const ownerTokenAccount = await fetchTokenAccount(provider, ownerToken);
+    assert.ok(ownerTokenAccount != null);
+
+    await program.rpc.withdrawVault(new anchor.BN(shares.toString()), { accounts: vaultAccounts });
+    ownerShareTokenAccount = await fetchTokenAccount(provider, ownerShareToken);
+    assert.ok(ownerShareTokenAccount != null);
+    assert.equal(ownerShareTokenAccount.amount, BigInt(0));
+
+    const newOwnerTokenAccount = await fetchTokenAccount(provider, ownerToken);
+    assert.ok(newOwnerTokenAccount != null);
+    assert.ok(newOwnerTokenAccount.amount > ownerTokenAccount.amount + BigInt(2e9));
   });
 
   it("Withdraws tokens", async () => {
     await sleep(5000 - Date.now() + stakeDoneTime);
 
-    const [stake,] = await getStakePublicKey();
-    await program.rpc.closeStakeAccount({
+    for (const { publicKey: stake } of await fetchStakePositions()) {
+      await program.rpc.closeStakeAccount({
+        accounts: {
+          stake,
+          escrow,
+          mint,
+          rewardMint: mint,
+          owner,
+          escrowToken,
+          ownerToken,
+          rewardToken,
+          ownerRewardToken: ownerToken,
+          tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+        },
+      });
+
+      const stakeAccount = await program.account.stake.fetchNullable(stake);
+      assert.ok(stakeAccount == null);
+    }
+
+    const stakePositions = await fetchStakePositions();
+    assert.equal(stakePositions.length, 0);
+
+    // The stakes of the owner receive all the rewards emitted while they were staked, at least 5 seconds worth of them,
+    // apart from rounding.
+    const escrowTokenAccount = await fetchTokenAccount(provider, escrowToken);
+    assert.ok(escrowTokenAccount != null);
+    assert.equal(escrowTokenAccount.amount, BigInt(0));
+
+    const rewardTokenAccount = await fetchTokenAccount(provider, rewardToken);
+    assert.ok(rewardTokenAccount != null);
+    const reward = BigInt(1e9) - rewardTokenAccount.amount;
+    assert.ok(reward >= BigInt(95));
+
+    const ownerTokenAccount = await fetchTokenAccount(provider, ownerToken);
+    assert.ok(ownerTokenAccount != null);
+    assert.equal(ownerTokenAccount.amount, BigInt(9e9) + reward);
+  });
+
+  it("Withdraws tokens in an emergency", async () => {
+    const [positions,] = await getPositionsPublicKey();
+    const [stake,] = await getStakePublicKey(3);
+    // Locked stakes can be withdrawn in an emergency as well.
+    await program.rpc.createStakeAccount(new anchor.BN(1e9), 3, {
       accounts: {
+        positions,
         stake,
         escrow,
         mint,
@@ -116,6 +621,43 @@ describe("staking", () => {
         escrowToken,
         ownerToken,
         tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+        systemProgram: web3.SystemProgram.programId,
+      },
+    });
+
+    // Part of the rewards not emitted yet can be withdrawn by the payer at any time.
+    const withdrawRewards = async (amount: bigint) => {
+      await program.rpc.withdrawRewards(new anchor.BN(amount.toString()), {
+        accounts: {
+          escrow,
+          payer: provider.wallet.publicKey,
+          mint,
+          rewardMint: mint,
+          rewardToken,
+          payerToken: ownerToken,
+          tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+        },
+      });
+    };
+    await withdrawRewards(BigInt(1e8));
+
+    await program.rpc.enableEmergency({
+      accounts: {
+        escrow,
+        payer: provider.wallet.publicKey,
+        mint,
+      },
+    });
+
+    await program.rpc.emergencyWithdraw({
+      accounts: {
+        stake,
+        escrow,
+        owner,
+        mint,
+        escrowToken,
+        ownerToken,
+        tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
       },
     });
 
@@ -124,11 +666,16 @@ describe("staking", () => {
 
     const escrowTokenAccount = await fetchTokenAccount(provider, escrowToken);
     assert.ok(escrowTokenAccount != null);
-    assert.equal(escrowTokenAccount.amount, BigInt(1e9-20));
+    assert.equal(escrowTokenAccount.amount, BigInt(0));
+
+    // The stakes forfeit their rewards, so the payer can withdraw the whole reward vault.
+    const rewardTokenAccount = await fetchTokenAccount(provider, rewardToken);
+    assert.ok(rewardTokenAccount != null);
+    await withdrawRewards(rewardTokenAccount.amount);
 
     const ownerTokenAccount = await fetchTokenAccount(provider, ownerToken);
     assert.ok(ownerTokenAccount != null);
-    assert.equal(ownerTokenAccount.amount, BigInt(9e9+20));
+    assert.equal(ownerTokenAccount.amount, BigInt(1e10));
   });
 });
 
@@ -139,6 +686,14 @@ function getEscrowPublicKey(programId: web3.PublicKey, mint: web3.PublicKey): [w
   );
 }
 
+function getEscrowTokenPublicKey(
+  programId: web3.PublicKey, seed: string, escrow: web3.PublicKey): [web3.PublicKey, number] {
+  return anchor.utils.publicKey.findProgramAddressSync(
+    [Buffer.from(seed), escrow.toBuffer()],
+    programId,
+  );
+}
+
 async function fetchTokenAccount(provider: anchor.Provider, publicKey: web3.PublicKey): Promise<tokenLib.RawAccount | null> {
   const accountInfo = await provider.connection.getAccountInfo(publicKey);
   return accountInfo == null ? null : tokenLib.AccountLayout.decode(accountInfo.data);
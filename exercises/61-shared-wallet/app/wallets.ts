@@ -0,0 +1,27 @@
+import * as anchor from "@project-serum/anchor";
+import { Program, web3 } from "@project-serum/anchor";
+
+import { SharedWallet } from "../target/types/shared_wallet";
+
+// Returns the address and the bump of the wallet created by the creator with the name.
+export function findWalletAddress(
+  program: Program<SharedWallet>, creator: web3.PublicKey, name: string): [web3.PublicKey, number] {
+  return anchor.utils.publicKey.findProgramAddressSync(
+    [
+      Buffer.from("wallet"),
+      creator.toBuffer(),
+      Buffer.from(name),
+    ],
+    program.programId,
+  );
+}
+
+// Returns the wallets the user is a user of. The users are stored after the variable length name of the wallet, so
+// they cannot be filtered with a memcmp filter and all the wallets are fetched instead.
+export async function findUserWallets(
+  program: Program<SharedWallet>, user: web3.PublicKey): Promise<web3.PublicKey[]> {
+  const wallets = await program.account.wallet.all();
+  return wallets
+    .filter(wallet => wallet.account.users.some(pk => pk.equals(user)))
+    .map(wallet => wallet.publicKey);
+}
@@ -1,23 +1,176 @@
-use anchor_lang::prelude::*;
+use anchor_lang::{
+    prelude::*,
+    solana_program::{
+        instruction::{AccountMeta, Instruction},
+        program::{invoke, invoke_signed},
+        system_instruction,
+    },
+};
 use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
 
 declare_id!("7W4Y4uNcffFFusKuxEbZr22CcjU1819LNK3iyVSqBRhb");
 
 pub const WALLET_ACCOUNT_SEED: &[u8] = b"wallet";
+pub const VAULT_ACCOUNT_SEED: &[u8] = b"vault";
+
+// Names are used as a seed of the wallet, which can be at most 32 bytes long.
+pub const MAX_NAME_LEN: usize = 32;
 
 #[program]
 pub mod shared_wallet {
     use super::*;
 
-    pub fn create(ctx: Context<Create>, users: Vec<Pubkey>) -> Result<()> {
+    pub fn create(ctx: Context<Create>, name: String, users: Vec<Pubkey>, threshold: u64) -> Result<()> {
+        require!(is_unique(&users), SharedWalletError::DuplicateUser);
+        require!(threshold > 0 && threshold <= users.len() as u64, SharedWalletError::InvalidThreshold);
+
         let wallet = &mut ctx.accounts.wallet;
+        wallet.creator = ctx.accounts.creator.key();
+        wallet.name = name;
         wallet.users = users.clone();
+        wallet.threshold = threshold;
         wallet.bump = *ctx.bumps.get("wallet").unwrap();
         Ok(())
     }
 
-    pub fn transfer_tokens(ctx: Context<TransferTokens>, amount: u64) -> Result<()> {
+    pub fn create_transaction(ctx: Context<CreateTransaction>, kind: TransactionKind) -> Result<()> {
+        let wallet = &ctx.accounts.wallet;
+        let proposer_index = wallet.user_index(ctx.accounts.proposer.key).unwrap();
+
+        // The proposer approves the transaction by proposing it.
+        let mut signers = vec![false; wallet.users.len()];
+        signers[proposer_index] = true;
+
+        let transaction = &mut ctx.accounts.transaction;
+        transaction.wallet = wallet.key();
+        transaction.proposer = ctx.accounts.proposer.key();
+        transaction.kind = kind;
+        transaction.signers = signers;
+        transaction.users_seqno = wallet.users_seqno;
+        transaction.did_execute = false;
+        Ok(())
+    }
+
+    pub fn approve(ctx: Context<Approve>) -> Result<()> {
+        let user_index = ctx.accounts.wallet.user_index(ctx.accounts.user.key).unwrap();
+        let transaction = &mut ctx.accounts.transaction;
+        transaction.check_pending(&ctx.accounts.wallet)?;
+
+        transaction.signers[user_index] = true;
+        Ok(())
+    }
+
+    pub fn add_member(ctx: Context<UpdateWallet>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let member = match accounts.transaction.kind {
+            TransactionKind::AddMember { member } => member,
+            _ => return err!(SharedWalletError::InvalidTransaction),
+        };
+        accounts.transaction.execute(&accounts.wallet)?;
+        require!(!accounts.wallet.users.contains(&member), SharedWalletError::DuplicateUser);
+
+        let wallet = &mut accounts.wallet;
+        wallet.users.push(member);
+        wallet.users_seqno += 1;
+
+        let space = wallet.current_space();
+        realloc_wallet(wallet, &accounts.user, &accounts.system_program, space)
+    }
+
+    pub fn remove_member(ctx: Context<UpdateWallet>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let member = match accounts.transaction.kind {
+            TransactionKind::RemoveMember { member } => member,
+            _ => return err!(SharedWalletError::InvalidTransaction),
+        };
+        accounts.transaction.execute(&accounts.wallet)?;
+        let member_index = accounts.wallet.user_index(&member).ok_or(SharedWalletError::InvalidUser)?;
+        require!(accounts.wallet.users.len() > 1, SharedWalletError::LastUser);
+
+        let wallet = &mut accounts.wallet;
+        wallet.users.remove(member_index);
+        wallet.spending_limits.retain(|limit| limit.member != member);
+        wallet.users_seqno += 1;
+        // Keep the wallet usable when the threshold is higher than the remaining number of users.
+        if wallet.threshold > wallet.users.len() as u64 {
+            wallet.threshold = wallet.users.len() as u64;
+        }
+
+        let space = wallet.current_space();
+        realloc_wallet(wallet, &accounts.user, &accounts.system_program, space)
+    }
+
+    pub fn change_threshold(ctx: Context<UpdateWallet>) -> Result<()> {
         let accounts = ctx.accounts;
+        let threshold = match accounts.transaction.kind {
+            TransactionKind::ChangeThreshold { threshold } => threshold,
+            _ => return err!(SharedWalletError::InvalidTransaction),
+        };
+        accounts.transaction.execute(&accounts.wallet)?;
+        require!(
+            threshold > 0 && threshold <= accounts.wallet.users.len() as u64,
+            SharedWalletError::InvalidThreshold,
+        );
+
+        accounts.wallet.threshold = threshold;
+        Ok(())
+    }
+
+    pub fn set_spending_limit(ctx: Context<UpdateWallet>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let (member, mint, amount, period) = match accounts.transaction.kind {
+            TransactionKind::SetSpendingLimit { member, mint, amount, period } => (member, mint, amount, period),
+            _ => return err!(SharedWalletError::InvalidTransaction),
+        };
+        accounts.transaction.execute(&accounts.wallet)?;
+        require!(accounts.wallet.users.contains(&member), SharedWalletError::InvalidUser);
+
+        let wallet = &mut accounts.wallet;
+        let limits = &mut wallet.spending_limits;
+        match limits.iter().position(|limit| limit.member == member && limit.mint == mint) {
+            // A zero amount removes the spending limit.
+            Some(index) if amount == 0 => {
+                limits.remove(index);
+            }
+            Some(index) => {
+                limits[index].amount = amount;
+                limits[index].period = period;
+            }
+            None if amount == 0 => {}
+            None => limits.push(SpendingLimit {
+                member,
+                mint,
+                amount,
+                period,
+                spent: 0,
+                period_start: 0,
+            }),
+        }
+
+        let space = wallet.current_space();
+        realloc_wallet(wallet, &accounts.user, &accounts.system_program, space)
+    }
+
+    pub fn transfer_tokens(ctx: Context<TransferTokens>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let amount = match accounts.transaction.kind {
+            TransactionKind::TransferTokens { mint, destination_token, amount }
+                if mint == accounts.mint.key() && destination_token == accounts.destination_token.key() => amount,
+            _ => return err!(SharedWalletError::InvalidTransaction),
+        };
+
+        let transaction = &mut accounts.transaction;
+        transaction.check_pending(&accounts.wallet)?;
+        if transaction.approvals() < accounts.wallet.threshold {
+            // Transfers without enough approvals can be executed by a user that approved them, within the spending
+            // limit of the user for the mint.
+            let user_index = accounts.wallet.user_index(accounts.user.key).unwrap();
+            require!(transaction.signers[user_index], SharedWalletError::NotEnoughApprovals);
+            let now = Clock::get()?.unix_timestamp;
+            accounts.wallet.spend(accounts.user.key, &accounts.mint.key(), amount, now)?;
+        }
+        transaction.did_execute = true;
+
         let cpi_program = accounts.token_program.to_account_info();
         let cpi_accounts = Transfer {
             from: accounts.wallet_token.to_account_info(),
@@ -28,29 +181,141 @@ pub mod shared_wallet {
             CpiContext::new_with_signer(
                 cpi_program,
                 cpi_accounts,
-                &[&[WALLET_ACCOUNT_SEED, &[accounts.wallet.bump]]],
+                &[&accounts.wallet.signer_seeds()],
             ),
             amount,
         )
     }
+
+    pub fn transfer_sol(ctx: Context<TransferSol>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let amount = match accounts.transaction.kind {
+            TransactionKind::TransferSol { destination, amount } if destination == accounts.destination.key() => amount,
+            _ => return err!(SharedWalletError::InvalidTransaction),
+        };
+        accounts.transaction.execute(&accounts.wallet)?;
+
+        let wallet_key = accounts.wallet.key();
+        invoke_signed(
+            &system_instruction::transfer(accounts.vault.key, accounts.destination.key, amount),
+            &[
+                accounts.vault.to_account_info(),
+                accounts.destination.to_account_info(),
+                accounts.system_program.to_account_info(),
+            ],
+            &[&[VAULT_ACCOUNT_SEED, wallet_key.as_ref(), &[*ctx.bumps.get("vault").unwrap()]]],
+        )?;
+        Ok(())
+    }
+
+    pub fn execute_transaction(ctx: Context<ExecuteTransaction>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let instruction = match &accounts.transaction.kind {
+            TransactionKind::Instruction { program_id, accounts: instruction_accounts, data } => Instruction {
+                program_id: *program_id,
+                accounts: instruction_accounts.iter().map(AccountMeta::from).collect(),
+                data: data.clone(),
+            },
+            _ => return err!(SharedWalletError::InvalidTransaction),
+        };
+        accounts.transaction.execute(&accounts.wallet)?;
+
+        // The accounts of the instruction, including the program, are passed as the remaining accounts. The wallet
+        // signs the instruction.
+        invoke_signed(&instruction, ctx.remaining_accounts, &[&accounts.wallet.signer_seeds()])?;
+        Ok(())
+    }
 }
 
 #[derive(Accounts)]
-#[instruction(users: Vec<Pubkey>)]
+#[instruction(name: String, users: Vec<Pubkey>)]
 pub struct Create<'info> {
+    // The name is checked before the wallet is derived from it, as longer seeds cannot be derived.
+    #[account(mut, constraint = name.len() <= MAX_NAME_LEN @ SharedWalletError::NameTooLong)]
+    pub creator: Signer<'info>,
     #[account(
         init,
-        payer = payer,
+        payer = creator,
         seeds = [
             WALLET_ACCOUNT_SEED,
+            creator.key().as_ref(),
+            name.as_bytes(),
         ],
-        space = Wallet::space(users.len()),
+        space = Wallet::space(&name, users.len(), 0),
         bump,
     )]
     pub wallet: Account<'info, Wallet>,
 
-    #[account(mut)]
-    pub payer: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(kind: TransactionKind)]
+pub struct CreateTransaction<'info> {
+    #[account(
+        seeds = [
+            WALLET_ACCOUNT_SEED,
+            wallet.creator.as_ref(),
+            wallet.name.as_bytes(),
+        ],
+        bump = wallet.bump,
+    )]
+    pub wallet: Account<'info, Wallet>,
+    #[account(
+        init,
+        payer = proposer,
+        space = Transaction::space(wallet.users.len(), &kind),
+    )]
+    pub transaction: Account<'info, Transaction>,
+
+    #[account(
+        mut,
+        constraint = wallet.users.contains(proposer.key) @ SharedWalletError::InvalidUser,
+    )]
+    pub proposer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Approve<'info> {
+    #[account(
+        seeds = [
+            WALLET_ACCOUNT_SEED,
+            wallet.creator.as_ref(),
+            wallet.name.as_bytes(),
+        ],
+        bump = wallet.bump,
+    )]
+    pub wallet: Account<'info, Wallet>,
+    #[account(mut, has_one = wallet)]
+    pub transaction: Account<'info, Transaction>,
+
+    #[account(constraint = wallet.users.contains(user.key) @ SharedWalletError::InvalidUser)]
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateWallet<'info> {
+    #[account(
+        mut,
+        seeds = [
+            WALLET_ACCOUNT_SEED,
+            wallet.creator.as_ref(),
+            wallet.name.as_bytes(),
+        ],
+        bump = wallet.bump,
+    )]
+    pub wallet: Account<'info, Wallet>,
+    #[account(mut, has_one = wallet)]
+    pub transaction: Account<'info, Transaction>,
+
+    // Pays for the additional space of the wallet, or receives the rent of the freed space.
+    #[account(
+        mut,
+        constraint = wallet.users.contains(user.key) @ SharedWalletError::InvalidUser,
+    )]
+    pub user: Signer<'info>,
 
     pub system_program: Program<'info, System>,
 }
@@ -58,14 +323,19 @@ pub struct Create<'info> {
 #[derive(Accounts)]
 pub struct TransferTokens<'info> {
     #[account(
+        mut,
         seeds = [
             WALLET_ACCOUNT_SEED,
+            wallet.creator.as_ref(),
+            wallet.name.as_bytes(),
         ],
-        bump,
+        bump = wallet.bump,
     )]
     pub wallet: Account<'info, Wallet>,
+    #[account(mut, has_one = wallet)]
+    pub transaction: Account<'info, Transaction>,
 
-    #[account(constraint = wallet.users.contains(user.key))]
+    #[account(constraint = wallet.users.contains(user.key) @ SharedWalletError::InvalidUser)]
     pub user: Signer<'info>,
     pub mint: Account<'info, Mint>,
 
@@ -85,15 +355,315 @@ pub struct TransferTokens<'info> {
     pub token_program: Program<'info, Token>,
 }
 
+#[derive(Accounts)]
+pub struct TransferSol<'info> {
+    #[account(
+        seeds = [
+            WALLET_ACCOUNT_SEED,
+            wallet.creator.as_ref(),
+            wallet.name.as_bytes(),
+        ],
+        bump = wallet.bump,
+    )]
+    pub wallet: Account<'info, Wallet>,
+    #[account(mut, has_one = wallet)]
+    pub transaction: Account<'info, Transaction>,
+
+    #[account(constraint = wallet.users.contains(user.key) @ SharedWalletError::InvalidUser)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [
+            VAULT_ACCOUNT_SEED,
+            wallet.key().as_ref(),
+        ],
+        bump,
+    )]
+    pub vault: SystemAccount<'info>,
+    /// CHECK: Any account can receive lamports, the account is checked against the transaction.
+    #[account(mut)]
+    pub destination: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteTransaction<'info> {
+    #[account(
+        seeds = [
+            WALLET_ACCOUNT_SEED,
+            wallet.creator.as_ref(),
+            wallet.name.as_bytes(),
+        ],
+        bump = wallet.bump,
+    )]
+    pub wallet: Account<'info, Wallet>,
+    #[account(mut, has_one = wallet)]
+    pub transaction: Account<'info, Transaction>,
+
+    #[account(constraint = wallet.users.contains(user.key) @ SharedWalletError::InvalidUser)]
+    pub user: Signer<'info>,
+}
+
 #[account]
 #[derive(Debug, Default, PartialEq, Eq)]
 pub struct Wallet {
+    pub creator: Pubkey,
+    pub name: String,
     pub users: Vec<Pubkey>,
+    // Number of users that have to approve a transaction before it can be executed.
+    pub threshold: u64,
+    // Incremented when the users change, which invalidates the pending transactions.
+    pub users_seqno: u32,
+    pub spending_limits: Vec<SpendingLimit>,
     pub bump: u8,
 }
 
 impl Wallet {
-    pub fn space(num_owners: usize) -> usize {
-        8 + 4 + num_owners * 32 + 1
+    pub fn space(name: &str, num_owners: usize, num_spending_limits: usize) -> usize {
+        8 + 32 + 4 + name.len() + 4 + num_owners * 32 + 8 + 4 + 4 + num_spending_limits * SpendingLimit::SPACE + 1
+    }
+
+    pub fn current_space(&self) -> usize {
+        Self::space(&self.name, self.users.len(), self.spending_limits.len())
+    }
+
+    pub fn signer_seeds(&self) -> [&[u8]; 4] {
+        [
+            WALLET_ACCOUNT_SEED,
+            self.creator.as_ref(),
+            self.name.as_bytes(),
+            std::slice::from_ref(&self.bump),
+        ]
+    }
+
+    pub fn user_index(&self, user: &Pubkey) -> Option<usize> {
+        self.users.iter().position(|u| u == user)
+    }
+
+    /// Spends the amount from the spending limit of the user for the mint.
+    pub fn spend(&mut self, user: &Pubkey, mint: &Pubkey, amount: u64, now: i64) -> Result<()> {
+        let limit = self
+            .spending_limits
+            .iter_mut()
+            .find(|limit| limit.member == *user && limit.mint == *mint)
+            .ok_or(SharedWalletError::NotEnoughApprovals)?;
+        limit.spend(amount, now)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SpendingLimit {
+    pub member: Pubkey,
+    pub mint: Pubkey,
+    // Amount the member can transfer in a period without the approval of the other users.
+    pub amount: u64,
+    pub period: SpendingPeriod,
+    pub spent: u64,
+    pub period_start: i64,
+}
+
+impl SpendingLimit {
+    pub const SPACE: usize = 32 + 32 + 8 + 1 + 8 + 8;
+
+    /// Spends the amount in the current period, starting a new period first if the current one has ended.
+    pub fn spend(&mut self, amount: u64, now: i64) -> Result<()> {
+        let period_start = self.period.start(now);
+        if period_start > self.period_start {
+            self.period_start = period_start;
+            self.spent = 0;
+        }
+
+        self.spent = self
+            .spent
+            .checked_add(amount)
+            .filter(|spent| *spent <= self.amount)
+            .ok_or(SharedWalletError::SpendingLimitExceeded)?;
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpendingPeriod {
+    Daily,
+    Weekly,
+}
+
+impl SpendingPeriod {
+    pub fn seconds(&self) -> i64 {
+        match self {
+            SpendingPeriod::Daily => 24 * 60 * 60,
+            SpendingPeriod::Weekly => 7 * 24 * 60 * 60,
+        }
+    }
+
+    /// Returns the start of the period containing the time. Periods are aligned to the unix epoch.
+    pub fn start(&self, now: i64) -> i64 {
+        now - now.rem_euclid(self.seconds())
+    }
+}
+
+#[account]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Transaction {
+    pub wallet: Pubkey,
+    pub proposer: Pubkey,
+    pub kind: TransactionKind,
+    // Approvals of the transaction, indexed like the users of the wallet.
+    pub signers: Vec<bool>,
+    pub did_execute: bool,
+    pub users_seqno: u32,
+}
+
+impl Transaction {
+    pub fn space(num_owners: usize, kind: &TransactionKind) -> usize {
+        8 + 32 + 32 + kind.space() + 4 + num_owners + 1 + 4
+    }
+
+    pub fn approvals(&self) -> u64 {
+        self.signers.iter().filter(|signed| **signed).count() as u64
+    }
+
+    /// Checks that the transaction has not been executed and that the users of the wallet have not changed since it
+    /// was created.
+    pub fn check_pending(&self, wallet: &Wallet) -> Result<()> {
+        require!(!self.did_execute, SharedWalletError::AlreadyExecuted);
+        require!(self.users_seqno == wallet.users_seqno, SharedWalletError::StaleTransaction);
+        Ok(())
     }
+
+    /// Marks the transaction as executed if it has been approved by enough users of the wallet.
+    pub fn execute(&mut self, wallet: &Wallet) -> Result<()> {
+        self.check_pending(wallet)?;
+        require!(self.approvals() >= wallet.threshold, SharedWalletError::NotEnoughApprovals);
+
+        self.did_execute = true;
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransactionKind {
+    TransferTokens {
+        mint: Pubkey,
+        destination_token: Pubkey,
+        amount: u64,
+    },
+    AddMember {
+        member: Pubkey,
+    },
+    RemoveMember {
+        member: Pubkey,
+    },
+    ChangeThreshold {
+        threshold: u64,
+    },
+    TransferSol {
+        destination: Pubkey,
+        amount: u64,
+    },
+    SetSpendingLimit {
+        member: Pubkey,
+        mint: Pubkey,
+        amount: u64,
+        period: SpendingPeriod,
+    },
+    Instruction {
+        program_id: Pubkey,
+        accounts: Vec<TransactionAccount>,
+        data: Vec<u8>,
+    },
+}
+
+impl TransactionKind {
+    pub fn space(&self) -> usize {
+        match self {
+            TransactionKind::TransferTokens { .. } => 1 + 32 + 32 + 8,
+            TransactionKind::AddMember { .. } | TransactionKind::RemoveMember { .. } => 1 + 32,
+            TransactionKind::ChangeThreshold { .. } => 1 + 8,
+            TransactionKind::TransferSol { .. } => 1 + 32 + 8,
+            TransactionKind::SetSpendingLimit { .. } => 1 + 32 + 32 + 8 + 1,
+            TransactionKind::Instruction { accounts, data, .. } => {
+                1 + 32 + 4 + accounts.len() * TransactionAccount::SPACE + 4 + data.len()
+            }
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TransactionAccount {
+    pub pubkey: Pubkey,
+    pub is_signer: bool,
+    pub is_writable: bool,
+}
+
+impl TransactionAccount {
+    pub const SPACE: usize = 32 + 1 + 1;
+}
+
+impl From<&TransactionAccount> for AccountMeta {
+    fn from(account: &TransactionAccount) -> Self {
+        AccountMeta {
+            pubkey: account.pubkey,
+            is_signer: account.is_signer,
+            is_writable: account.is_writable,
+        }
+    }
+}
+
+fn is_unique(users: &[Pubkey]) -> bool {
+    users.iter().enumerate().all(|(i, user)| !users[..i].contains(user))
+}
+
+/// Resizes the wallet to the space, keeping it rent exempt. The payer pays for the additional space, or receives the
+/// rent of the freed space.
+fn realloc_wallet<'info>(
+    wallet: &Account<'info, Wallet>,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    space: usize,
+) -> Result<()> {
+    let wallet_info = wallet.to_account_info();
+    let rent = Rent::get()?.minimum_balance(space);
+    let lamports = wallet_info.lamports();
+    if rent > lamports {
+        invoke(
+            &system_instruction::transfer(payer.key, wallet_info.key, rent - lamports),
+            &[
+                payer.to_account_info(),
+                wallet_info.clone(),
+                system_program.to_account_info(),
+            ],
+        )?;
+    } else {
+        **wallet_info.try_borrow_mut_lamports()? -= lamports - rent;
+        **payer.to_account_info().try_borrow_mut_lamports()? += lamports - rent;
+    }
+    wallet_info.realloc(space, false)?;
+    Ok(())
+}
+
+#[error_code]
+pub enum SharedWalletError {
+    #[msg("The name of the wallet is too long.")]
+    NameTooLong,
+    #[msg("The user is not a user of the wallet.")]
+    InvalidUser,
+    #[msg("The users of the wallet must be unique.")]
+    DuplicateUser,
+    #[msg("The threshold must be between 1 and the number of users.")]
+    InvalidThreshold,
+    #[msg("The transaction does not match the accounts.")]
+    InvalidTransaction,
+    #[msg("The transaction has already been executed.")]
+    AlreadyExecuted,
+    #[msg("The transaction does not have enough approvals.")]
+    NotEnoughApprovals,
+    #[msg("The users of the wallet have changed since the transaction was created.")]
+    StaleTransaction,
+    #[msg("The last user of the wallet cannot be removed.")]
+    LastUser,
+    #[msg("The transfer exceeds the spending limit of the user.")]
+    SpendingLimitExceeded,
 }
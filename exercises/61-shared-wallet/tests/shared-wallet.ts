@@ -3,6 +3,7 @@ import * as anchor from "@project-serum/anchor";
 import { BN, Program, web3 } from "@project-serum/anchor";
 import * as tokenLib from "@solana/spl-token";
 
+import { findUserWallets, findWalletAddress } from "../app/wallets";
 import { SharedWallet } from "../target/types/shared_wallet";
 
 describe("shared-wallet", () => {
@@ -10,9 +11,14 @@ describe("shared-wallet", () => {
   anchor.setProvider(provider);
 
   const program = anchor.workspace.SharedWallet as Program<SharedWallet>;
-  const [wallet, walletBump] = anchor.utils.publicKey.findProgramAddressSync(
+  const walletName = "team";
+  const [wallet, walletBump] = findWalletAddress(program, provider.wallet.publicKey, walletName);
+  const otherWalletName = "operations";
+  const [otherWallet] = findWalletAddress(program, provider.wallet.publicKey, otherWalletName);
+  const [vault] = anchor.utils.publicKey.findProgramAddressSync(
     [
-      Buffer.from("wallet"),
+      Buffer.from("vault"),
+      wallet.toBuffer(),
     ],
     program.programId,
   );
@@ -30,10 +36,26 @@ describe("shared-wallet", () => {
   });
 
   it("Creates wallet", async () => {
-    await program.rpc.create([user1.publicKey, user2.publicKey, user3.publicKey], {
+    // Threshold should not be greater than the number of users.
+    let rejected = false;
+    try {
+      await program.rpc.create(walletName, [user1.publicKey, user2.publicKey, user3.publicKey], new BN(4), {
+        accounts: {
+          creator: provider.wallet.publicKey,
+          wallet,
+          systemProgram: web3.SystemProgram.programId,
+        },
+      });
+    } catch (e: any) {
+      rejected = true;
+      assert.equal(e?.code, 6003);
+    }
+    assert.ok(rejected);
+
+    await program.rpc.create(walletName, [user1.publicKey, user2.publicKey, user3.publicKey], new BN(2), {
       accounts: {
+        creator: provider.wallet.publicKey,
         wallet,
-        payer: provider.wallet.publicKey,
         systemProgram: web3.SystemProgram.programId,
       },
     });
@@ -43,50 +65,470 @@ describe("shared-wallet", () => {
       walletAccount.users.map(pk => pk.toString()),
       [user1.publicKey.toString(), user2.publicKey.toString(), user3.publicKey.toString()],
     );
+    assert.equal(walletAccount.creator.toString(), provider.wallet.publicKey.toString());
+    assert.equal(walletAccount.name, walletName);
+    assert.equal(walletAccount.threshold.toNumber(), 2);
     assert.equal(walletAccount.bump, walletBump);
   });
 
+  it("Creates multiple wallets", async () => {
+    await program.rpc.create(otherWalletName, [user1.publicKey], new BN(1), {
+      accounts: {
+        creator: provider.wallet.publicKey,
+        wallet: otherWallet,
+        systemProgram: web3.SystemProgram.programId,
+      },
+    });
+
+    const user1Wallets = await findUserWallets(program, user1.publicKey);
+    assert.deepEqual(
+      user1Wallets.map(pk => pk.toString()).sort(),
+      [wallet.toString(), otherWallet.toString()].sort(),
+    );
+
+    const user2Wallets = await findUserWallets(program, user2.publicKey);
+    assert.deepEqual(user2Wallets.map(pk => pk.toString()), [wallet.toString()]);
+  });
+
   it("Transfers spl token", async () => {
     const destination = web3.Keypair.generate();
     const destinationToken = await createAssociatedTokenAccount(provider, mint, destination.publicKey);
 
     const randomUser = web3.Keypair.generate();
+    await airdrop(provider, randomUser.publicKey);
+    await airdrop(provider, user1.publicKey);
 
+    const kind = { transferTokens: { mint, destinationToken, amount: new BN(1000) } };
+
+    // Only authorized users should be allowed to propose transactions.
+    let rejected = false;
     try {
-      await program.rpc.transferTokens(new BN(1000), {
+      const transaction = web3.Keypair.generate();
+      await program.rpc.createTransaction(kind, {
         accounts: {
           wallet,
+          transaction: transaction.publicKey,
+          proposer: randomUser.publicKey,
+          systemProgram: web3.SystemProgram.programId,
+        },
+        signers: [randomUser, transaction],
+      });
+    } catch (e: any) {
+      rejected = true;
+      assert.equal(e?.code, 6001);
+    }
+    assert.ok(rejected);
+
+    const transaction = web3.Keypair.generate();
+    await program.rpc.createTransaction(kind, {
+      accounts: {
+        wallet,
+        transaction: transaction.publicKey,
+        proposer: user1.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      },
+      signers: [user1, transaction],
+    });
+
+    let transactionAccount = await program.account.transaction.fetch(transaction.publicKey);
+    assert.deepEqual(transactionAccount.signers, [true, false, false]);
+    assert.equal(transactionAccount.didExecute, false);
+
+    const transferAccounts = {
+      wallet,
+      transaction: transaction.publicKey,
+      user: user1.publicKey,
+      mint,
+      walletToken,
+      destinationToken,
+      tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+    };
+
+    // Transfer should not be executed before reaching the threshold.
+    rejected = false;
+    try {
+      await program.rpc.transferTokens({
+        accounts: transferAccounts,
+        signers: [user1],
+      });
+    } catch (e: any) {
+      rejected = true;
+      assert.equal(e?.code, 6006);
+    }
+    assert.ok(rejected);
+
+    // Only authorized users should be allowed to approve.
+    rejected = false;
+    try {
+      await program.rpc.approve({
+        accounts: {
+          wallet,
+          transaction: transaction.publicKey,
           user: randomUser.publicKey,
-          mint,
-          walletToken,
-          destinationToken,
-          tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
         },
         signers: [randomUser],
       });
-      assert.fail("Only authorized users should be allowed to transfer");
-    } catch (e) {}
+    } catch (e: any) {
+      rejected = true;
+      assert.equal(e?.code, 6001);
+    }
+    assert.ok(rejected);
 
-    await program.rpc.transferTokens(new BN(1000), {
+    await program.rpc.approve({
       accounts: {
         wallet,
-        user: user1.publicKey,
-        mint,
-        walletToken,
-        destinationToken,
-        tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+        transaction: transaction.publicKey,
+        user: user2.publicKey,
       },
+      signers: [user2],
+    });
+
+    transactionAccount = await program.account.transaction.fetch(transaction.publicKey);
+    assert.deepEqual(transactionAccount.signers, [true, true, false]);
+
+    await program.rpc.transferTokens({
+      accounts: transferAccounts,
       signers: [user1],
     });
 
+    transactionAccount = await program.account.transaction.fetch(transaction.publicKey);
+    assert.equal(transactionAccount.didExecute, true);
+
     const walletTokenAccount = await fetchTokenAccount(provider, walletToken);
     assert.equal(walletTokenAccount.amount, BigInt(1e9 - 1000));
 
     const destinationTokenAccount = await fetchTokenAccount(provider, destinationToken);
     assert.equal(destinationTokenAccount.amount, BigInt(1000));
+
+    // Transfer should not be executed twice.
+    rejected = false;
+    try {
+      await program.rpc.transferTokens({
+        accounts: transferAccounts,
+        signers: [user1],
+      });
+    } catch (e: any) {
+      rejected = true;
+      assert.equal(e?.code, 6005);
+    }
+    assert.ok(rejected);
+  });
+
+  it("Transfers sol", async () => {
+    const destination = web3.Keypair.generate();
+    await provider.send(new web3.Transaction().add(
+      web3.SystemProgram.transfer({
+        fromPubkey: provider.wallet.publicKey,
+        toPubkey: vault,
+        lamports: web3.LAMPORTS_PER_SOL,
+      }),
+    ));
+
+    const transaction = await proposeTransaction(
+      program, wallet, { transferSol: { destination: destination.publicKey, amount: new BN(1e6) } }, user1);
+    const transferAccounts = {
+      wallet,
+      transaction,
+      user: user1.publicKey,
+      vault,
+      destination: destination.publicKey,
+      systemProgram: web3.SystemProgram.programId,
+    };
+
+    // Transfer should not be executed before reaching the threshold.
+    let rejected = false;
+    try {
+      await program.rpc.transferSol({
+        accounts: transferAccounts,
+        signers: [user1],
+      });
+    } catch (e: any) {
+      rejected = true;
+      assert.equal(e?.code, 6006);
+    }
+    assert.ok(rejected);
+
+    await approveTransaction(program, wallet, transaction, user3);
+    await program.rpc.transferSol({
+      accounts: transferAccounts,
+      signers: [user1],
+    });
+
+    assert.equal(await provider.connection.getBalance(vault), web3.LAMPORTS_PER_SOL - 1e6);
+    assert.equal(await provider.connection.getBalance(destination.publicKey), 1e6);
+  });
+
+  it("Transfers spl token within spending limit", async () => {
+    const destination = web3.Keypair.generate();
+    const destinationToken = await createAssociatedTokenAccount(provider, mint, destination.publicKey);
+    await airdrop(provider, user2.publicKey);
+
+    const limitTransaction = await proposeTransaction(program, wallet, {
+      setSpendingLimit: { member: user1.publicKey, mint, amount: new BN(500), period: { daily: {} } },
+    }, user1);
+    await approveTransaction(program, wallet, limitTransaction, user2);
+    await program.rpc.setSpendingLimit({
+      accounts: {
+        wallet,
+        transaction: limitTransaction,
+        user: user1.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      },
+      signers: [user1],
+    });
+
+    const walletAccount = await program.account.wallet.fetch(wallet);
+    assert.equal(walletAccount.spendingLimits.length, 1);
+    assert.equal(walletAccount.spendingLimits[0].member.toString(), user1.publicKey.toString());
+    assert.equal(walletAccount.spendingLimits[0].amount.toNumber(), 500);
+
+    const transfer = async (user: web3.Keypair) => {
+      const transaction = await proposeTransaction(
+        program, wallet, { transferTokens: { mint, destinationToken, amount: new BN(300) } }, user);
+      await program.rpc.transferTokens({
+        accounts: {
+          wallet,
+          transaction,
+          user: user.publicKey,
+          mint,
+          walletToken,
+          destinationToken,
+          tokenProgram: tokenLib.TOKEN_PROGRAM_ID,
+        },
+        signers: [user],
+      });
+    };
+
+    await transfer(user1);
+    const destinationTokenAccount = await fetchTokenAccount(provider, destinationToken);
+    assert.equal(destinationTokenAccount.amount, BigInt(300));
+
+    // Transfers over the spending limit should require approvals.
+    let rejected = false;
+    try {
+      await transfer(user1);
+    } catch (e: any) {
+      rejected = true;
+      assert.equal(e?.code, 6009);
+    }
+    assert.ok(rejected);
+
+    // Users without a spending limit should require approvals.
+    rejected = false;
+    try {
+      await transfer(user2);
+    } catch (e: any) {
+      rejected = true;
+      assert.equal(e?.code, 6006);
+    }
+    assert.ok(rejected);
+
+    // The rejected transfers did not move any tokens.
+    assert.equal((await fetchTokenAccount(provider, destinationToken)).amount, BigInt(300));
+  });
+
+  it("Executes arbitrary instruction", async () => {
+    const destination = web3.Keypair.generate();
+    const destinationToken = await createAssociatedTokenAccount(provider, mint, destination.publicKey);
+
+    const instruction = tokenLib.createTransferInstruction(walletToken, destinationToken, wallet, 100);
+    const transaction = await proposeTransaction(program, wallet, {
+      instruction: {
+        programId: instruction.programId,
+        accounts: instruction.keys.map(meta => ({
+          pubkey: meta.pubkey,
+          isSigner: meta.pubkey.equals(wallet) || meta.isSigner,
+          isWritable: meta.isWritable,
+        })),
+        data: instruction.data,
+      },
+    }, user1);
+
+    // The wallet signs through the program, so it is not a signer of the outer transaction.
+    const remainingAccounts = instruction.keys
+      .map(meta => meta.pubkey.equals(wallet) ? { ...meta, isSigner: false } : meta)
+      .concat({ pubkey: instruction.programId, isSigner: false, isWritable: false });
+    const executeTransaction = async () => {
+      await program.rpc.executeTransaction({
+        accounts: {
+          wallet,
+          transaction,
+          user: user1.publicKey,
+        },
+        remainingAccounts,
+        signers: [user1],
+      });
+    };
+
+    // Transaction should not be executed before reaching the threshold.
+    let rejected = false;
+    try {
+      await executeTransaction();
+    } catch (e: any) {
+      rejected = true;
+      assert.equal(e?.code, 6006);
+    }
+    assert.ok(rejected);
+
+    await approveTransaction(program, wallet, transaction, user3);
+    await executeTransaction();
+
+    const destinationTokenAccount = await fetchTokenAccount(provider, destinationToken);
+    assert.equal(destinationTokenAccount.amount, BigInt(100));
+  });
+
+  it("Manages members", async () => {
+    const user4 = web3.Keypair.generate();
+
+    const staleTransaction = await proposeTransaction(
+      program, wallet, { changeThreshold: { threshold: new BN(3) } }, user1);
+    await approveTransaction(program, wallet, staleTransaction, user2);
+
+    const addTransaction = await proposeTransaction(program, wallet, { addMember: { member: user4.publicKey } }, user1);
+    await approveTransaction(program, wallet, addTransaction, user3);
+    await program.rpc.addMember({
+      accounts: {
+        wallet,
+        transaction: addTransaction,
+        user: user1.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      },
+      signers: [user1],
+    });
+
+    let walletAccount = await program.account.wallet.fetch(wallet);
+    assert.deepEqual(
+      walletAccount.users.map(pk => pk.toString()),
+      [user1.publicKey.toString(), user2.publicKey.toString(), user3.publicKey.toString(), user4.publicKey.toString()],
+    );
+
+    // Transactions created before the users changed should not be executed.
+    let rejected = false;
+    try {
+      await program.rpc.changeThreshold({
+        accounts: {
+          wallet,
+          transaction: staleTransaction,
+          user: user1.publicKey,
+          systemProgram: web3.SystemProgram.programId,
+        },
+        signers: [user1],
+      });
+    } catch (e: any) {
+      rejected = true;
+      assert.equal(e?.code, 6007);
+    }
+    assert.ok(rejected);
+
+    const duplicateTransaction = await proposeTransaction(
+      program, wallet, { addMember: { member: user2.publicKey } }, user1);
+    await approveTransaction(program, wallet, duplicateTransaction, user2);
+    // Users should not be added twice.
+    rejected = false;
+    try {
+      await program.rpc.addMember({
+        accounts: {
+          wallet,
+          transaction: duplicateTransaction,
+          user: user1.publicKey,
+          systemProgram: web3.SystemProgram.programId,
+        },
+        signers: [user1],
+      });
+    } catch (e: any) {
+      rejected = true;
+      assert.equal(e?.code, 6002);
+    }
+    assert.ok(rejected);
+
+    const removeTransaction = await proposeTransaction(
+      program, wallet, { removeMember: { member: user3.publicKey } }, user1);
+    await approveTransaction(program, wallet, removeTransaction, user4);
+    await program.rpc.removeMember({
+      accounts: {
+        wallet,
+        transaction: removeTransaction,
+        user: user1.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      },
+      signers: [user1],
+    });
+
+    const thresholdTransaction = await proposeTransaction(
+      program, wallet, { changeThreshold: { threshold: new BN(3) } }, user1);
+    await approveTransaction(program, wallet, thresholdTransaction, user2);
+    await program.rpc.changeThreshold({
+      accounts: {
+        wallet,
+        transaction: thresholdTransaction,
+        user: user1.publicKey,
+        systemProgram: web3.SystemProgram.programId,
+      },
+      signers: [user1],
+    });
+
+    walletAccount = await program.account.wallet.fetch(wallet);
+    assert.deepEqual(
+      walletAccount.users.map(pk => pk.toString()),
+      [user1.publicKey.toString(), user2.publicKey.toString(), user4.publicKey.toString()],
+    );
+    assert.equal(walletAccount.threshold.toNumber(), 3);
+
+    const lastTransaction = await proposeTransaction(
+      program, otherWallet, { removeMember: { member: user1.publicKey } }, user1);
+    // The last user should not be removed.
+    rejected = false;
+    try {
+      await program.rpc.removeMember({
+        accounts: {
+          wallet: otherWallet,
+          transaction: lastTransaction,
+          user: user1.publicKey,
+          systemProgram: web3.SystemProgram.programId,
+        },
+        signers: [user1],
+      });
+    } catch (e: any) {
+      rejected = true;
+      assert.equal(e?.code, 6008);
+    }
+    assert.ok(rejected);
   });
 });
 
+async function proposeTransaction(
+  program: Program<SharedWallet>, wallet: web3.PublicKey, kind: any, proposer: web3.Keypair): Promise<web3.PublicKey> {
+  const transaction = web3.Keypair.generate();
+  await program.rpc.createTransaction(kind, {
+    accounts: {
+      wallet,
+      transaction: transaction.publicKey,
+      proposer: proposer.publicKey,
+      systemProgram: web3.SystemProgram.programId,
+    },
+    signers: [proposer, transaction],
+  });
+  return transaction.publicKey;
+}
+
+async function approveTransaction(
+  program: Program<SharedWallet>, wallet: web3.PublicKey, transaction: web3.PublicKey, user: web3.Keypair,
+): Promise<void> {
+  await program.rpc.approve({
+    accounts: {
+      wallet,
+      transaction,
+      user: user.publicKey,
+    },
+    signers: [user],
+  });
+}
+
+async function airdrop(provider: anchor.Provider, publicKey: web3.PublicKey): Promise<void> {
+  const signature = await provider.connection.requestAirdrop(publicKey, web3.LAMPORTS_PER_SOL);
+  await provider.connection.confirmTransaction(signature);
+}
+
 async function fetchTokenAccount(
   provider: anchor.Provider, publicKey: web3.PublicKey): Promise<tokenLib.RawAccount | null> {
   const accountInfo = await provider.connection.getAccountInfo(publicKey);